@@ -1,7 +1,8 @@
-use super::error::ParserError;
+use super::diagnostic::{Code, Diagnostic, Span};
 use super::lexer::Token;
-use super::symbol_table::SymbolTable;
-use crate::instruction::Opcode;
+use super::symbol_table::{SymbolTable, SymbolType};
+use super::Section;
+use crate::instruction::{Instruction, Opcode, OperandKind, MAX_OPERANDS};
 
 pub struct Compiler<'a> {
     c: usize,
@@ -9,18 +10,22 @@ pub struct Compiler<'a> {
     tokens: &'a Vec<Token>,
     symbol_table: &'a SymbolTable,
     compiled: Vec<u8>,
-    pub errors: Vec<ParserError>,
+    data: Vec<u8>,
+    section: Section,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl<'a> Compiler<'a> {
-    pub fn new<'b>(tokens: &'a Vec<Token>, symbol_table: &'a SymbolTable) -> Compiler<'a> {
+    pub fn new(tokens: &'a Vec<Token>, symbol_table: &'a SymbolTable) -> Compiler<'a> {
         Compiler {
             tokens,
             symbol_table,
             c: 0,
-            errors: vec![],
-            current: Token::RegisterNum((0, 0, 0)),
+            diagnostics: vec![],
+            current: Token::RegisterNum((0, Span::on_line(1, 1, 1))),
             compiled: vec![],
+            data: vec![],
+            section: Section::Code,
         }
     }
 
@@ -28,220 +33,198 @@ impl<'a> Compiler<'a> {
         self.compiled.clone()
     }
 
+    /// Returns the read-only data emitted by data directives in the `.data` section.
+    pub fn get_data(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
     /// Compiles all tokens.
     pub fn compile_all(&mut self) {
-        while !self.is_end() && !(self.tokens.len() <= self.c + 1) {
+        while !self.is_end() {
             self.compile();
         }
     }
 
     /// Compiles one token.
     pub fn compile(&mut self) {
-        self.current = self.tokens[self.c].clone();
-
-        if self.c != 0 {
-            self.advance();
-        }
+        self.advance();
 
         match self.current.clone() {
-            Token::Opcode(opcode) => match opcode.0 {
-                Opcode::IGL => self.add_error("expected an opcode", opcode.1, opcode.2),
-                Opcode::HLT => self.compiled.push(opcode.0 as u8),
-                Opcode::LOAD => {
-                    self.compiled.push(opcode.0 as u8);
-
-                    let mut register: u8 = 0;
-                    let mut number: i32 = 0;
-
-                    self.advance();
-                    match self.current.clone() {
-                        Token::RegisterNum(register_num) => register = register_num.0,
-                        Token::Opcode(t) => {
-                            self.add_error("expected a register number", t.1, t.2);
-                        }
-                        Token::IntegerOperand(t) => {
-                            self.add_error("expected a register number", t.1, t.2);
-                        }
-                        Token::FloatOperand(t) => {
-                            self.add_error("expected a register number", t.1, t.2);
-                        }
-                        Token::LabelDeclaration(t) => {
-                            self.add_error("expected a register number", t.1, t.2);
-                        }
-                        Token::LabelUsage(t) => {
-                            self.add_error("expected a register number", t.1, t.2);
-                        }
-                        Token::Directive(t) => {
-                            self.add_error("expected a register number", t.1, t.2);
-                        }
-                    }
-                    self.compiled.push(register);
-
-                    self.advance();
-                    match self.current.clone() {
-                        Token::IntegerOperand(num) => number = num.0,
-                        Token::Opcode(t) => {
-                            self.add_error("expected an operand", t.1, t.2);
-                        }
-                        Token::FloatOperand(t) => {
-                            self.add_error("expected an operand", t.1, t.2);
-                        }
-                        Token::RegisterNum(t) => {
-                            self.add_error("expected an operand", t.1, t.2);
-                        }
-
-                        Token::LabelDeclaration(t) => {
-                            self.add_error("expected an operand", t.1, t.2);
-                        }
-                        Token::LabelUsage(t) => {
-                            self.add_error("expected an operand", t.1, t.2);
-                        }
-                        Token::Directive(t) => {
-                            self.add_error("expected an operand", t.1, t.2);
-                        }
-                    }
-
-                    for b in Compiler::extract_int_operand(number) {
-                        self.compiled.push(b);
+            Token::Opcode(opcode) if self.section == Section::Data => {
+                self.add_error(
+                    Code::InstructionInData,
+                    "instructions must be in the .code section",
+                    opcode.1,
+                );
+            }
+            Token::Opcode(opcode) => match opcode.0.info() {
+                Some(info) => {
+                    let mut operands = [0; MAX_OPERANDS];
+                    for (i, kind) in info.operands.iter().enumerate() {
+                        operands[i] = self.compile_operand(*kind, &opcode);
                     }
+                    Instruction::with_operands(opcode.0, &operands[..info.operands.len()])
+                        .encode(&mut self.compiled);
                 }
-                Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
-                    self.compiled.push(opcode.0 as u8);
+                None => self.add_error(Code::UnknownOpcode, "unknown opcode", opcode.1),
+            },
+            Token::Identifier(t) => self.add_error(Code::UnknownOpcode, "unknown opcode", t.1),
+            // Labels were already recorded in the symbol table by the first phase.
+            Token::LabelDeclaration(_) => {}
+            Token::Directive(t) => self.compile_directive(&t),
+            t => self.add_error(Code::ExpectedOpcode, "expected an opcode", t.span()),
+        }
+    }
 
-                    let mut register: u8 = 0;
+    /// Compiles the next token as an operand of the given kind and returns its value.
+    fn compile_operand(&mut self, kind: OperandKind, opcode: &(Opcode, Span)) -> u64 {
+        let msg = match kind {
+            OperandKind::Register => "expected a register number",
+            OperandKind::FloatRegister => "expected a float register number",
+            OperandKind::Number | OperandKind::Address | OperandKind::DataOffset => {
+                "expected an operand"
+            }
+            OperandKind::Float => "expected a float operand",
+        };
+        if self.is_end() {
+            self.add_error(Code::ExpectedOperand, msg, opcode.1);
+            return 0;
+        }
 
-                    for _ in 0..3 {
-                        self.advance();
-                        match self.current.clone() {
-                            Token::RegisterNum(register_num) => register = register_num.0,
-                            Token::Opcode(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                            Token::IntegerOperand(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                            Token::FloatOperand(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                            Token::LabelDeclaration(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                            Token::LabelUsage(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                            Token::Directive(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                        }
-                        self.compiled.push(register);
-                    }
+        self.advance();
+        match (kind, self.current.clone()) {
+            (OperandKind::Register, Token::RegisterNum(register)) => u64::from(register.0),
+            (OperandKind::FloatRegister, Token::FloatRegisterNum(register)) => {
+                u64::from(register.0)
+            }
+            (
+                OperandKind::Number | OperandKind::Address | OperandKind::DataOffset,
+                Token::IntegerOperand(number),
+            ) => {
+                if u16::try_from(number.0).is_err() {
+                    self.diagnostics.push(Diagnostic::warning(
+                        Code::OperandTruncated,
+                        &format!(
+                            "operand does not fit in 16 bits and is truncated to {}",
+                            number.0 as u16
+                        ),
+                        number.1,
+                    ));
                 }
-                Opcode::JMP
-                | Opcode::JMPF
-                | Opcode::JMPB
-                | Opcode::JEQ
-                | Opcode::JNEQ
-                | Opcode::ALOC
-                | Opcode::INC
-                | Opcode::DEC => {
-                    self.compiled.push(opcode.0 as u8);
-
-                    let mut target_pc: u8 = 0;
+                u64::from(number.0 as u16)
+            }
+            (
+                OperandKind::Number | OperandKind::Address | OperandKind::DataOffset,
+                Token::LabelUsage(label),
+            ) => u64::from(self.resolve_label(&label, kind) as u16),
+            (OperandKind::Float, Token::FloatOperand(number)) => number.0.to_bits(),
+            (OperandKind::Float, Token::IntegerOperand(number)) => f64::from(number.0).to_bits(),
+            (_, t) => {
+                self.add_error(Code::ExpectedOperand, msg, t.span());
+                0
+            }
+        }
+    }
 
+    /// Compiles a directive together with its operands.
+    fn compile_directive(&mut self, directive: &(String, Span)) {
+        let is_data = matches!(directive.0.as_str(), "asciiz" | "byte" | "word");
+        if is_data && self.section == Section::Code {
+            // The operands are still checked so that their errors are reported too.
+            self.add_error(
+                Code::DataInCode,
+                "data directives must be in the .data section",
+                directive.1,
+            );
+        }
+        match directive.0.as_str() {
+            "code" => self.section = Section::Code,
+            "data" => self.section = Section::Data,
+            "asciiz" => match self.tokens.get(self.c).cloned() {
+                Some(Token::StringLiteral(string)) => {
                     self.advance();
-                    match self.current.clone() {
-                        Token::IntegerOperand(target) => target_pc = target.0 as u8,
-                        Token::Opcode(t) => self.add_error("expected an operand", t.1, t.2),
-                        Token::FloatOperand(t) => {
-                            self.add_error("expected an operand", t.1, t.2);
-                        }
-
-                        Token::RegisterNum(t) => {
-                            self.add_error("expected an operand", t.1, t.2);
-                        }
-                        Token::LabelDeclaration(t) => {
-                            self.add_error("expected an operand", t.1, t.2);
-                        }
-                        Token::LabelUsage(t) => {
-                            self.add_error("expected an operand", t.1, t.2);
-                        }
-                        Token::Directive(t) => {
-                            self.add_error("expected an operand", t.1, t.2);
-                        }
-                    }
-                    self.compiled.push(target_pc);
+                    let output = self.output();
+                    output.extend_from_slice(string.0.as_bytes());
+                    output.push(0);
                 }
-                Opcode::EQ => {
-                    self.compiled.push(opcode.0 as u8);
-
-                    let mut register: u8 = 0;
-
-                    for _ in 0..2 {
-                        self.advance();
-                        match self.current.clone() {
-                            Token::RegisterNum(register_num) => register = register_num.0,
-                            Token::Opcode(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                            Token::IntegerOperand(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                            Token::FloatOperand(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                            Token::LabelDeclaration(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                            Token::LabelUsage(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                            Token::Directive(t) => {
-                                self.add_error("expected a register number", t.1, t.2);
-                            }
-                        }
-                        self.compiled.push(register);
+                _ => self.add_error(Code::ExpectedString, "expected a string", directive.1),
+            },
+            "byte" | "word" => {
+                let mut count = 0;
+                while let Some(Token::IntegerOperand(number)) = self.tokens.get(self.c).cloned() {
+                    self.advance();
+                    count += 1;
+                    if directive.0 == "word" {
+                        self.output().extend_from_slice(&number.0.to_be_bytes());
+                    } else if let Ok(byte) = u8::try_from(number.0) {
+                        self.output().push(byte);
+                    } else {
+                        self.add_error(Code::ValueOutOfRange, "should be u8", number.1);
+                        self.output().push(0);
                     }
                 }
-            },
-            Token::RegisterNum(t) => {
-                self.add_error("expected an opcode", t.1, t.2);
-            }
-            Token::IntegerOperand(t) => {
-                self.add_error("expected an opcode", t.1, t.2);
-            }
-            Token::FloatOperand(t) => {
-                self.add_error("expected an opcode", t.1, t.2);
+                if count == 0 {
+                    self.add_error(Code::ExpectedOperand, "expected an operand", directive.1);
+                }
             }
-            Token::LabelDeclaration(_) | Token::LabelUsage(_) | Token::Directive(_) => todo!(),
+            _ => self.add_error(Code::UnknownDirective, "unknown directive", directive.1),
         }
     }
 
-    fn add_error(&mut self, msg: &str, line: usize, col: usize) {
-        self.errors.push(ParserError::new(msg, line, col));
+    /// Returns the bytes of the current section.
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self.section {
+            Section::Code => &mut self.compiled,
+            Section::Data => &mut self.data,
+        }
     }
 
-    fn extract_int_operand(i: i32) -> [u8; 2] {
-        let converted = i as u16;
-        let byte1 = converted;
-        let byte2 = converted >> 8;
-        // little endian rule
-        [byte2 as u8, byte1 as u8]
+    fn add_error(&mut self, code: Code, msg: &str, span: Span) {
+        self.diagnostics.push(Diagnostic::error(code, msg, span));
     }
 
-    fn is_end(&self) -> bool {
-        if self.tokens.len() <= self.c {
-            true
-        } else {
-            false
+    /// Returns the offset of the label used as an operand of the given kind, or adds an error if
+    /// it has not been declared or points into the wrong section.
+    fn resolve_label(&mut self, label: &(String, Span), kind: OperandKind) -> i32 {
+        match self.symbol_table.symbol(&label.0) {
+            Some(symbol) => {
+                let expected = match kind {
+                    OperandKind::Address => Some((SymbolType::Label, "code")),
+                    OperandKind::DataOffset => Some((SymbolType::Data, "data")),
+                    _ => None,
+                };
+                match expected {
+                    Some((symbol_type, section)) if *symbol.symbol_type() != symbol_type => {
+                        self.add_error(
+                            Code::WrongLabelKind,
+                            &format!("label '{}' is not in the .{} section", label.0, section),
+                            label.1,
+                        );
+                        0
+                    }
+                    _ => symbol.offset() as i32,
+                }
+            }
+            None => {
+                self.add_error(
+                    Code::UndefinedLabel,
+                    format!("undefined label '{}'", label.0).as_str(),
+                    label.1,
+                );
+                0
+            }
         }
     }
 
+    fn is_end(&self) -> bool {
+        self.tokens.len() <= self.c
+    }
+
+    /// Moves `current` to the next token.
     fn advance(&mut self) {
-        if !self.is_end() && !(self.tokens.len() <= self.c + 1) {
-            self.c += 1;
+        if !self.is_end() {
             self.current = self.tokens[self.c].clone();
-        } else {
-            self.c = self.tokens.len();
+            self.c += 1;
         }
     }
 }
@@ -250,20 +233,21 @@ impl<'a> Compiler<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::symbol_table::Symbol;
 
     #[test]
     fn test_compiler() {
         let tokens = vec![
-            Token::Opcode((Opcode::LOAD, 1, 5)),
-            Token::RegisterNum((0, 1, 8)),
-            Token::IntegerOperand((500, 1, 13)),
-            Token::Opcode((Opcode::LOAD, 2, 5)),
-            Token::RegisterNum((1, 2, 8)),
-            Token::IntegerOperand((100, 2, 13)),
-            Token::Opcode((Opcode::ADD, 3, 4)),
-            Token::RegisterNum((0, 3, 7)),
-            Token::RegisterNum((1, 3, 10)),
-            Token::RegisterNum((2, 3, 12)),
+            Token::Opcode((Opcode::LOAD, Span::on_line(1, 1, 5))),
+            Token::RegisterNum((0, Span::on_line(1, 6, 8))),
+            Token::IntegerOperand((500, Span::on_line(1, 9, 13))),
+            Token::Opcode((Opcode::LOAD, Span::on_line(2, 1, 5))),
+            Token::RegisterNum((1, Span::on_line(2, 6, 8))),
+            Token::IntegerOperand((100, Span::on_line(2, 9, 13))),
+            Token::Opcode((Opcode::ADD, Span::on_line(3, 1, 4))),
+            Token::RegisterNum((0, Span::on_line(3, 5, 7))),
+            Token::RegisterNum((1, Span::on_line(3, 8, 10))),
+            Token::RegisterNum((2, Span::on_line(3, 11, 13))),
         ];
         // Load 500 to register 0, load 100 to register 1, add registers 0 and 1, and stores the
         // result to register 2.
@@ -277,7 +261,92 @@ mod tests {
 
         let compiled = compiler.get_compiled_program();
 
-        assert_eq!(compiler.errors.len(), 0);
+        assert_eq!(compiler.diagnostics.len(), 0);
         assert_eq!(expected, *compiled);
     }
+
+    #[test]
+    fn test_compile_call_stack() {
+        let tokens = vec![
+            Token::Opcode((Opcode::PUSH, Span::on_line(1, 1, 5))),
+            Token::RegisterNum((0, Span::on_line(1, 6, 8))),
+            Token::Opcode((Opcode::CALL, Span::on_line(2, 1, 5))),
+            Token::IntegerOperand((300, Span::on_line(2, 6, 10))),
+            Token::Opcode((Opcode::POP, Span::on_line(3, 1, 4))),
+            Token::RegisterNum((1, Span::on_line(3, 5, 7))),
+            Token::Opcode((Opcode::RET, Span::on_line(4, 1, 4))),
+            Token::Opcode((Opcode::HLT, Span::on_line(5, 1, 4))),
+        ];
+        let expected: Vec<u8> = vec![17, 0, 15, 1, 44, 18, 1, 16, 5];
+
+        let symbol_table = SymbolTable::new();
+
+        let mut compiler = Compiler::new(&tokens, &symbol_table);
+
+        compiler.compile_all();
+
+        assert_eq!(compiler.diagnostics.len(), 0);
+        assert_eq!(expected, compiler.get_compiled_program());
+    }
+
+    #[test]
+    fn test_operand_truncated() {
+        let tokens = vec![
+            Token::Opcode((Opcode::CALL, Span::on_line(1, 1, 5))),
+            Token::IntegerOperand((65537, Span::on_line(1, 6, 12))),
+        ];
+        let symbol_table = SymbolTable::new();
+        let mut compiler = Compiler::new(&tokens, &symbol_table);
+        compiler.compile_all();
+
+        assert_eq!(
+            compiler.diagnostics,
+            vec![Diagnostic::warning(
+                Code::OperandTruncated,
+                "operand does not fit in 16 bits and is truncated to 1",
+                Span::on_line(1, 6, 12)
+            )]
+        );
+        assert_eq!(compiler.get_compiled_program(), vec![15, 0, 1]);
+    }
+
+    #[test]
+    fn test_wrong_label_kind() {
+        // `CALL @msg` calls into the data, and `PRTS @start` prints the code.
+        let tokens = vec![
+            Token::Opcode((Opcode::CALL, Span::on_line(1, 1, 5))),
+            Token::LabelUsage((String::from("msg"), Span::on_line(1, 6, 10))),
+            Token::Opcode((Opcode::PRTS, Span::on_line(2, 1, 5))),
+            Token::LabelUsage((String::from("start"), Span::on_line(2, 6, 12))),
+            Token::Opcode((Opcode::LOAD, Span::on_line(3, 1, 5))),
+            Token::RegisterNum((0, Span::on_line(3, 6, 8))),
+            Token::LabelUsage((String::from("msg"), Span::on_line(3, 9, 13))),
+        ];
+        let mut symbol_table = SymbolTable::new();
+        symbol_table.add_symbol(Symbol::new(String::from("msg"), SymbolType::Data, 2));
+        symbol_table.add_symbol(Symbol::new(String::from("start"), SymbolType::Label, 0));
+        let mut compiler = Compiler::new(&tokens, &symbol_table);
+        compiler.compile_all();
+
+        // Plain numbers can be either kind.
+        assert_eq!(
+            compiler.diagnostics,
+            vec![
+                Diagnostic::error(
+                    Code::WrongLabelKind,
+                    "label 'msg' is not in the .code section",
+                    Span::on_line(1, 6, 10)
+                ),
+                Diagnostic::error(
+                    Code::WrongLabelKind,
+                    "label 'start' is not in the .data section",
+                    Span::on_line(2, 6, 12)
+                ),
+            ]
+        );
+        assert_eq!(
+            compiler.get_compiled_program(),
+            vec![15, 0, 0, 43, 0, 0, 0, 0, 0, 2]
+        );
+    }
 }
@@ -0,0 +1,354 @@
+use std::fmt;
+use std::iter;
+
+/// A position in the source. Lines and columns start at 1, and columns count characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Position {
+    pub fn new(line: usize, col: usize) -> Self {
+        Position { line, col }
+    }
+}
+
+/// A range of source text from `start` up to, but not including, `end`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+    /// The file the text is in: 0 for the file being assembled, and `n` for the `n`th file
+    /// included with `.include`.
+    pub file: usize,
+    /// The macro expansion that produced the token, as an index into
+    /// `Expander::expansions`.
+    pub expansion: Option<usize>,
+}
+
+impl Span {
+    /// Returns a span in the file being assembled, outside of any macro expansion.
+    pub fn new(start: Position, end: Position) -> Self {
+        Span {
+            start,
+            end,
+            file: 0,
+            expansion: None,
+        }
+    }
+
+    /// Returns a span on one line from column `start` up to, but not including, column `end`.
+    pub fn on_line(line: usize, start: usize, end: usize) -> Self {
+        Span::new(Position::new(line, start), Position::new(line, end))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    /// Additional information about the diagnostic before it.
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// Identifies the kind of an error or warning, so that tools can match on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    /// A character that cannot start a token.
+    InvalidCharacter,
+    /// A register or number that cannot be parsed.
+    InvalidNumber,
+    /// A string literal without a closing quote.
+    UnterminatedString,
+    /// A backslash in a string literal followed by an unknown character.
+    UnknownEscape,
+    /// A mnemonic that is not in the instruction table.
+    UnknownOpcode,
+    /// A token where an instruction or directive should start.
+    ExpectedOpcode,
+    /// A missing operand or one of the wrong kind.
+    ExpectedOperand,
+    /// A label that is used but never declared.
+    UndefinedLabel,
+    /// A label that is declared more than once.
+    DuplicateLabel,
+    /// A directive that the assembler does not know.
+    UnknownDirective,
+    /// An instruction in the `.data` section.
+    InstructionInData,
+    /// `.asciiz` without a string.
+    ExpectedString,
+    /// A data value that does not fit in its directive.
+    ValueOutOfRange,
+    /// A malformed or misplaced macro definition.
+    InvalidMacro,
+    /// A macro definition without `.endm`.
+    UnterminatedMacro,
+    /// A macro called with the wrong number or kind of arguments.
+    MacroArguments,
+    /// A `%name` that is not a parameter of the macro it is used in.
+    UnknownMacroParameter,
+    /// Macros or includes that expand themselves without end.
+    ExpansionTooDeep,
+    /// A file that cannot be included.
+    IncludeFailed,
+    /// A code label used as a data offset, or a data label used as an address.
+    WrongLabelKind,
+    /// A data directive in the `.code` section.
+    DataInCode,
+    /// An operand that does not fit in its 16 bits and is truncated.
+    OperandTruncated,
+}
+
+impl Code {
+    /// Returns the code as written in diagnostics, e.g. `E0007`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Code::InvalidCharacter => "E0001",
+            Code::InvalidNumber => "E0002",
+            Code::UnterminatedString => "E0003",
+            Code::UnknownEscape => "E0004",
+            Code::UnknownOpcode => "E0005",
+            Code::ExpectedOpcode => "E0006",
+            Code::ExpectedOperand => "E0007",
+            Code::UndefinedLabel => "E0008",
+            Code::DuplicateLabel => "E0009",
+            Code::UnknownDirective => "E0010",
+            Code::InstructionInData => "E0011",
+            Code::ExpectedString => "E0012",
+            Code::ValueOutOfRange => "E0013",
+            Code::InvalidMacro => "E0014",
+            Code::UnterminatedMacro => "E0015",
+            Code::MacroArguments => "E0016",
+            Code::UnknownMacroParameter => "E0017",
+            Code::ExpansionTooDeep => "E0018",
+            Code::IncludeFailed => "E0019",
+            Code::WrongLabelKind => "E0020",
+            Code::DataInCode => "E0021",
+            Code::OperandTruncated => "W0001",
+        }
+    }
+}
+
+/// An error, warning or note about a span of assembly source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Notes have no code.
+    pub code: Option<Code>,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn error(code: Code, message: &str, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code: Some(code),
+            message: String::from(message),
+            span,
+        }
+    }
+
+    pub fn warning(code: Code, message: &str, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            code: Some(code),
+            message: String::from(message),
+            span,
+        }
+    }
+
+    pub fn note(message: &str, span: Span) -> Self {
+        Diagnostic {
+            severity: Severity::Note,
+            code: None,
+            message: String::from(message),
+            span,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Formats the diagnostic on one line, e.g. `a.fasm:1:6: error[E0008]: undefined label`.
+    pub fn format(&self, filename: &str) -> String {
+        format!(
+            "{}:{}:{}: {}",
+            filename,
+            self.span.start.line,
+            self.span.start.col,
+            self.heading()
+        )
+    }
+
+    /// Formats the diagnostic with the line of `source` it refers to, underlining the span:
+    ///
+    /// ```text
+    /// error[E0008]: undefined label 'nowhere'
+    ///  --> a.fasm:1:6
+    ///   |
+    /// 1 | CALL @nowhere
+    ///   |      ^^^^^^^^
+    /// ```
+    pub fn render(&self, filename: &str, source: &str) -> String {
+        let start = self.span.start;
+        let number = start.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let mut text = format!(
+            "{}\n{}--> {}:{}:{}\n",
+            self.heading(),
+            gutter,
+            filename,
+            start.line,
+            start.col
+        );
+
+        if let Some(line) = source.lines().nth(start.line.saturating_sub(1)) {
+            let line_len = line.chars().count();
+            // Underline up to the end of the span, or of the line if the span continues.
+            let end = if self.span.end.line == start.line {
+                self.span.end.col.min(line_len + 1)
+            } else {
+                line_len + 1
+            };
+            let carets = end.saturating_sub(start.col).max(1);
+            // Keep tabs in the padding so that the carets line up however tabs are shown.
+            let padding: String = line
+                .chars()
+                .chain(iter::repeat(' '))
+                .take(start.col.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            text.push_str(&format!("{} |\n", gutter));
+            text.push_str(&format!("{} | {}\n", number, line));
+            text.push_str(&format!("{} | {}{}\n", gutter, padding, "^".repeat(carets)));
+        }
+        text
+    }
+
+    /// Formats the diagnostic as a JSON object for editors and other tools.
+    pub fn to_json(&self, filename: &str) -> String {
+        let code = match self.code {
+            Some(code) => format!("\"{}\"", code.as_str()),
+            None => String::from("null"),
+        };
+        format!(
+            "{{\"file\":{},\"severity\":\"{}\",\"code\":{},\"message\":{},\
+             \"start\":{{\"line\":{},\"col\":{}}},\"end\":{{\"line\":{},\"col\":{}}}}}",
+            json_string(filename),
+            self.severity,
+            code,
+            json_string(&self.message),
+            self.span.start.line,
+            self.span.start.col,
+            self.span.end.line,
+            self.span.end.col
+        )
+    }
+
+    /// Returns e.g. `error[E0008]: undefined label 'nowhere'`.
+    fn heading(&self) -> String {
+        match self.code {
+            Some(code) => format!("{}[{}]: {}", self.severity, code.as_str(), self.message),
+            None => format!("{}: {}", self.severity, self.message),
+        }
+    }
+}
+
+/// Quotes and escapes a string for JSON.
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn undefined_label() -> Diagnostic {
+        Diagnostic::error(
+            Code::UndefinedLabel,
+            "undefined label 'nowhere'",
+            Span::on_line(2, 6, 14),
+        )
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            undefined_label().format("a.fasm"),
+            "a.fasm:2:6: error[E0008]: undefined label 'nowhere'"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let expected = "error[E0008]: undefined label 'nowhere'
+ --> a.fasm:2:6
+  |
+2 | CALL @nowhere
+  |      ^^^^^^^^
+";
+        assert_eq!(
+            undefined_label().render("a.fasm", "HLT\nCALL @nowhere\n"),
+            expected
+        );
+
+        // Tabs before the span are kept so that the carets line up.
+        let indented = Diagnostic::note("here", Span::on_line(1, 3, 6));
+        assert_eq!(
+            indented.render("a.fasm", "\t\tHLT"),
+            "note: here\n --> a.fasm:1:3\n  |\n1 | \t\tHLT\n  | \t\t^^^\n"
+        );
+
+        // An empty span at the end of a line still gets a caret.
+        let note = Diagnostic::note("here", Span::on_line(1, 4, 4));
+        assert_eq!(
+            note.render("a.fasm", "HLT"),
+            "note: here\n --> a.fasm:1:4\n  |\n1 | HLT\n  |    ^\n"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let note = Diagnostic::note("say \"hi\"\n", Span::on_line(1, 1, 2));
+        assert_eq!(
+            undefined_label().to_json("a.fasm"),
+            "{\"file\":\"a.fasm\",\"severity\":\"error\",\"code\":\"E0008\",\
+             \"message\":\"undefined label 'nowhere'\",\"start\":{\"line\":2,\"col\":6},\
+             \"end\":{\"line\":2,\"col\":14}}"
+        );
+        assert_eq!(
+            note.to_json("a.fasm"),
+            "{\"file\":\"a.fasm\",\"severity\":\"note\",\"code\":null,\
+             \"message\":\"say \\\"hi\\\"\\n\",\"start\":{\"line\":1,\"col\":1},\
+             \"end\":{\"line\":1,\"col\":2}}"
+        );
+    }
+}
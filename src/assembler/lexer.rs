@@ -1,22 +1,59 @@
-use super::error::ParserError;
+use super::diagnostic::{Code, Diagnostic, Position, Span};
 use crate::instruction::Opcode;
+use std::str::Chars;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
-    Opcode((Opcode, usize, usize)),
-    RegisterNum((u8, usize, usize)),
-    IntegerOperand((i32, usize, usize)),
-    FloatOperand((f64, usize, usize)),
-    LabelDeclaration((String, usize, usize)),
-    LabelUsage((String, usize, usize)),
-    Directive((String, usize, usize)),
+    Opcode((Opcode, Span)),
+    RegisterNum((u8, Span)),
+    FloatRegisterNum((u8, Span)),
+    IntegerOperand((i32, Span)),
+    FloatOperand((f64, Span)),
+    LabelDeclaration((String, Span)),
+    LabelUsage((String, Span)),
+    Directive((String, Span)),
+    StringLiteral((String, Span)),
+    /// A word that is not an instruction mnemonic, such as the name of a macro.
+    Identifier((String, Span)),
+    /// A reference to a macro parameter, written `%name`.
+    MacroParameter((String, Span)),
 }
 
 impl Token {
     pub fn is_label(&self) -> bool {
+        matches!(self, Token::LabelDeclaration(_) | Token::LabelUsage(_))
+    }
+
+    /// Returns where in the source the token was read from.
+    pub fn span(&self) -> Span {
+        match self {
+            Token::Opcode(t) => t.1,
+            Token::RegisterNum(t) => t.1,
+            Token::FloatRegisterNum(t) => t.1,
+            Token::IntegerOperand(t) => t.1,
+            Token::FloatOperand(t) => t.1,
+            Token::LabelDeclaration(t) => t.1,
+            Token::LabelUsage(t) => t.1,
+            Token::Directive(t) => t.1,
+            Token::StringLiteral(t) => t.1,
+            Token::Identifier(t) => t.1,
+            Token::MacroParameter(t) => t.1,
+        }
+    }
+
+    pub fn span_mut(&mut self) -> &mut Span {
         match self {
-            Token::LabelDeclaration(_) | Token::LabelUsage(_) => true,
-            _ => false,
+            Token::Opcode(t) => &mut t.1,
+            Token::RegisterNum(t) => &mut t.1,
+            Token::FloatRegisterNum(t) => &mut t.1,
+            Token::IntegerOperand(t) => &mut t.1,
+            Token::FloatOperand(t) => &mut t.1,
+            Token::LabelDeclaration(t) => &mut t.1,
+            Token::LabelUsage(t) => &mut t.1,
+            Token::Directive(t) => &mut t.1,
+            Token::StringLiteral(t) => &mut t.1,
+            Token::Identifier(t) => &mut t.1,
+            Token::MacroParameter(t) => &mut t.1,
         }
     }
 
@@ -28,26 +65,33 @@ impl Token {
     }
 }
 
-pub struct Lexer {
-    source: String,
-    pub errors: Vec<ParserError>,
+/// Reads the source one character at a time, so that tokenizing takes linear time.
+pub struct Lexer<'a> {
+    chars: Chars<'a>,
+    pub diagnostics: Vec<Diagnostic>,
     tokens: Vec<Token>,
     line: usize,
     col: usize,
-    c: usize,
+    /// The character at `line` and `col`, unless `at_end` is set
     current: char,
+    at_end: bool,
 }
 
-impl Lexer {
-    pub fn new(source: &str) -> Self {
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let mut chars = source.chars();
+        let (current, at_end) = match chars.next() {
+            Some(c) => (c, false),
+            None => (' ', true),
+        };
         Lexer {
-            source: String::from(source),
-            errors: vec![],
+            chars,
+            diagnostics: vec![],
             tokens: vec![],
             line: 1,
             col: 1,
-            c: 0,
-            current: ' ',
+            current,
+            at_end,
         }
     }
 
@@ -57,159 +101,299 @@ impl Lexer {
 
     /// Tokenizes the given source code
     pub fn tokenize(&mut self) {
-        self.current = self.source.chars().nth(self.c).unwrap();
-
+        // Every branch consumes at least one character, and leaves `current` at the first
+        // character after the token.
         while !self.is_end() {
-            if self.c != 0 {
-                self.advance();
-            }
+            let start = self.position();
             if self.current.is_alphabetic() {
-                // Opcode or label declaration
-                let mut opcode = String::new();
-                while self.current != ' '
-                    && self.current != '\t'
-                    && self.current != '\n'
-                    && (self.current == '_' || self.current.is_alphabetic())
-                    && !self.is_end()
-                {
-                    opcode.push(self.current);
-                    self.advance();
-                }
+                // Opcode, identifier or label declaration
+                let name = self.read_while(is_name_char);
+                let span = Span::new(start, self.position());
 
                 // Check if it's a label or not
-                if self.current == ':' {
-                    let label = Token::LabelDeclaration((opcode, self.line, self.col));
-                    self.tokens.push(label);
+                if !self.is_end() && self.current == ':' {
                     self.advance();
+                    self.tokens.push(Token::LabelDeclaration((name, span)));
+                } else if let Some(opcode) = Opcode::from_mnemonic(&name) {
+                    self.tokens.push(Token::Opcode((opcode, span)));
                 } else {
-                    let opcode = Self::match_opcode(opcode.as_str());
-                    self.tokens
-                        .push(Token::Opcode((opcode, self.line, self.col)));
+                    self.tokens.push(Token::Identifier((name, span)));
                 }
             } else if self.current == '$' {
-                // Register number
+                // Register number or float register number
                 self.advance();
-                let mut register = String::new();
-                while self.current.is_numeric() && !self.is_end() {
-                    register.push(self.current);
+                let is_float = !self.is_end() && self.current == 'f';
+                if is_float {
                     self.advance();
                 }
+                let register = self.read_while(|c| c.is_ascii_digit());
+                let span = Span::new(start, self.position());
 
                 let register: u8 = register.parse().unwrap_or_else(|_| {
-                    self.add_error("should be u8");
+                    self.add_error(Code::InvalidNumber, "should be u8", span);
                     0
                 });
-                self.tokens
-                    .push(Token::RegisterNum((register, self.line, self.col)));
+                if is_float {
+                    self.tokens.push(Token::FloatRegisterNum((register, span)));
+                } else {
+                    self.tokens.push(Token::RegisterNum((register, span)));
+                }
             } else if self.current == '#' {
-                // Integer operand
+                // Integer or float operand
                 self.advance();
-                let mut number = String::new();
-                // Check for '-'
-                if self.current == '-' {
-                    number.push(self.current);
-                    self.advance();
-                }
-
-                while self.current.is_numeric() && !self.is_end() {
-                    number.push(self.current);
-                    self.advance();
-                }
-
-                let number: i32 = number.parse().unwrap_or_else(|_| {
-                    self.add_error("should be i32");
-                    0
-                });
-                self.tokens
-                    .push(Token::IntegerOperand((number, self.line, self.col)));
+                self.lex_number(start);
             } else if self.current == '@' {
                 // Label usage
                 self.advance();
-                let mut label = String::new();
-                while self.current != ' '
-                    && self.current != '\t'
-                    && self.current != '\n'
-                    && (self.current == '_' || self.current.is_alphabetic())
-                    && !self.is_end()
-                {
-                    label.push(self.current);
-                    self.advance();
-                }
-                let label = Token::LabelUsage((label, self.line, self.col));
-                self.tokens.push(label);
+                let label = self.read_while(is_name_char);
+                let span = Span::new(start, self.position());
+                self.tokens.push(Token::LabelUsage((label, span)));
+            } else if self.current == '%' {
+                // Macro parameter
+                self.advance();
+                let name = self.read_while(|c| c == '_' || c.is_alphabetic());
+                let span = Span::new(start, self.position());
+                self.tokens.push(Token::MacroParameter((name, span)));
             } else if self.current == '.' {
                 // Directive
                 self.advance();
-                let mut directive = String::new();
-                while self.current != ' '
-                    && self.current != '\t'
-                    && self.current != '\n'
-                    && (self.current == '_' || self.current.is_alphabetic())
-                    && !self.is_end()
-                {
-                    directive.push(self.current);
+                let directive = self.read_while(|c| c == '_' || c.is_alphabetic());
+                let span = Span::new(start, self.position());
+                self.tokens.push(Token::Directive((directive, span)));
+            } else if self.current == '"' {
+                // String literal
+                self.advance();
+                let mut string = String::new();
+                let mut terminated = false;
+                while !self.is_end() {
+                    let c = self.current;
                     self.advance();
+                    match c {
+                        '"' => {
+                            terminated = true;
+                            break;
+                        }
+                        '\\' => {
+                            if let Some(c) = self.read_escape('"') {
+                                string.push(c);
+                            }
+                        }
+                        c => string.push(c),
+                    }
                 }
-                let directive = Token::Directive((directive, self.line, self.col));
-                self.tokens.push(directive);
-            } else if self.current == ' ' || self.current == '\n' || self.current == '\t' {
-                // do nothing
+                let span = Span::new(start, self.position());
+                if !terminated {
+                    self.add_error(Code::UnterminatedString, "unterminated string", span);
+                }
+                self.tokens.push(Token::StringLiteral((string, span)));
+            } else if self.current == ';' {
+                // Comment until the end of the line
+                self.read_while(|c| c != '\n');
+            } else if [' ', '\n', '\t', ','].contains(&self.current) {
+                // Commas between operands are optional, and are skipped like whitespace.
+                self.advance();
             } else {
-                self.add_error("invalid character");
+                self.advance();
+                let span = Span::new(start, self.position());
+                self.add_error(Code::InvalidCharacter, "invalid character", span);
             }
         }
     }
 
-    fn add_error(&mut self, msg: &str) {
-        self.errors.push(ParserError::new(msg, self.line, self.col));
+    /// Lexes the operand after a `#` that starts at `start`: a decimal, `0x` hexadecimal or
+    /// `0b` binary integer, a float, or a character literal such as `'A'`. Digits may be
+    /// separated by underscores.
+    fn lex_number(&mut self, start: Position) {
+        let negative = !self.is_end() && self.current == '-';
+        if negative {
+            self.advance();
+        }
+
+        if !self.is_end() && self.current == '\'' {
+            self.advance();
+            let value = self.read_char_literal(start);
+            let value = if negative { -value } else { value };
+            let span = Span::new(start, self.position());
+            self.tokens.push(Token::IntegerOperand((value, span)));
+            return;
+        }
+
+        let mut number = String::new();
+        if negative {
+            number.push('-');
+        }
+        let digits = self.read_while(|c| c == '_' || c.is_ascii_alphanumeric());
+        let radix = if digits.starts_with("0x") || digits.starts_with("0X") {
+            16
+        } else if digits.starts_with("0b") || digits.starts_with("0B") {
+            2
+        } else {
+            10
+        };
+        let digits = if radix == 10 {
+            &digits[..]
+        } else {
+            &digits[2..]
+        };
+        number.extend(digits.chars().filter(|&c| c != '_'));
+
+        // Float operand, with a fraction, an exponent or both, or `inf` or `NaN`
+        let mut is_float = false;
+        if radix == 10 && !self.is_end() && self.current == '.' {
+            number.push(self.current);
+            self.advance();
+            let fraction = self.read_while(|c| c == '_' || c.is_ascii_alphanumeric());
+            number.extend(fraction.chars().filter(|&c| c != '_'));
+            is_float = true;
+        }
+        if radix == 10
+            && number.ends_with(['e', 'E'])
+            && !self.is_end()
+            && (self.current == '-' || self.current == '+')
+        {
+            number.push(self.current);
+            self.advance();
+            let exponent = self.read_while(|c| c == '_' || c.is_ascii_digit());
+            number.extend(exponent.chars().filter(|&c| c != '_'));
+        }
+        if radix == 10 {
+            is_float |= number.contains(['e', 'E'])
+                || ["inf", "nan"]
+                    .iter()
+                    .any(|word| digits.eq_ignore_ascii_case(word));
+        }
+
+        if is_float {
+            let span = Span::new(start, self.position());
+
+            let number: f64 = number.parse().unwrap_or_else(|_| {
+                self.add_error(Code::InvalidNumber, "should be f64", span);
+                0.0
+            });
+            self.tokens.push(Token::FloatOperand((number, span)));
+            return;
+        }
+
+        let span = Span::new(start, self.position());
+        let value = match i64::from_str_radix(&number, radix) {
+            Ok(value) if i32::try_from(value).is_ok() => Some(value as i32),
+            // Hexadecimal and binary literals may spell out all 32 bits.
+            Ok(value) if radix != 10 && u32::try_from(value).is_ok() => Some(value as u32 as i32),
+            _ => None,
+        };
+        let value = value.unwrap_or_else(|| {
+            self.add_error(Code::InvalidNumber, "should be i32", span);
+            0
+        });
+        self.tokens.push(Token::IntegerOperand((value, span)));
+    }
+
+    /// Reads the rest of a character literal after its opening quote, and returns the code of
+    /// the character.
+    fn read_char_literal(&mut self, start: Position) -> i32 {
+        let mut chars = vec![];
+        let mut terminated = false;
+        while !self.is_end() && self.current != '\n' {
+            let c = self.current;
+            self.advance();
+            match c {
+                '\'' => {
+                    terminated = true;
+                    break;
+                }
+                '\\' => chars.extend(self.read_escape('\'')),
+                c => chars.push(c),
+            }
+        }
+
+        let span = Span::new(start, self.position());
+        if !terminated {
+            self.add_error(
+                Code::UnterminatedString,
+                "unterminated character literal",
+                span,
+            );
+        } else if chars.len() != 1 {
+            self.add_error(
+                Code::InvalidNumber,
+                "character literals must hold one character",
+                span,
+            );
+        }
+        chars.first().map_or(0, |&c| c as i32)
     }
 
-    fn match_opcode(op: &str) -> Opcode {
-        match op.to_uppercase().as_str() {
-            "HLT" => Opcode::HLT,
-            "LOAD" => Opcode::LOAD,
-            "ADD" => Opcode::ADD,
-            "SUB" => Opcode::SUB,
-            "MUL" => Opcode::MUL,
-            "DIV" => Opcode::DIV,
-            "JMP" => Opcode::JMP,
-            "JMPF" => Opcode::JMPF,
-            "JMPB" => Opcode::JMPB,
-            "EQ" => Opcode::EQ,
-            "JEQ" => Opcode::JEQ,
-            "JNEQ" => Opcode::JNEQ,
-            "ALOC" => Opcode::ALOC,
-            "INC" => Opcode::INC,
-            "DEC" => Opcode::DEC,
-            _ => Opcode::IGL,
+    /// Reads the character after a backslash in a literal closed by `quote`, and returns the
+    /// character it stands for, or adds an error if it is not a known escape.
+    fn read_escape(&mut self, quote: char) -> Option<char> {
+        // The backslash was just consumed, so it is one column back.
+        let escape_start = Position::new(self.line, self.col - 1);
+        if self.is_end() {
+            return None;
+        }
+        let c = match self.current {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            '0' => Some('\0'),
+            c if c == '\\' || c == quote => Some(c),
+            _ => None,
+        };
+        self.advance();
+        if c.is_none() {
+            let span = Span::new(escape_start, self.position());
+            self.add_error(Code::UnknownEscape, "unknown escape sequence", span);
         }
+        c
+    }
+
+    fn add_error(&mut self, code: Code, msg: &str, span: Span) {
+        self.diagnostics.push(Diagnostic::error(code, msg, span));
     }
 
     fn is_end(&self) -> bool {
-        if self.source.len() <= self.c {
-            true
-        } else {
-            false
+        self.at_end
+    }
+
+    /// Returns the position of `current`.
+    fn position(&self) -> Position {
+        Position::new(self.line, self.col)
+    }
+
+    /// Consumes characters as long as `f` accepts them, and returns them.
+    fn read_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let mut read = String::new();
+        while !self.is_end() && f(self.current) {
+            read.push(self.current);
+            self.advance();
         }
+        read
     }
 
-    fn advance(&mut self) -> char {
-        if !self.is_end() && !(self.source.len() <= self.c + 1) {
-            if self.current == '\n' {
-                self.line += 1;
-                self.col = 1;
-            } else {
-                self.col += 1;
-            }
-            self.c += 1;
-            self.current = self.source.chars().nth(self.c).unwrap();
+    /// Moves `current` to the next character. Past the last character, only the position moves.
+    fn advance(&mut self) {
+        if self.is_end() {
+            return;
+        }
+        if self.current == '\n' {
+            self.line += 1;
+            self.col = 1;
         } else {
-            self.c = self.source.len();
+            self.col += 1;
+        }
+        match self.chars.next() {
+            Some(c) => self.current = c,
+            None => self.at_end = true,
         }
-        self.current
     }
 }
 
+/// Returns whether `c` can follow the first letter of a name. Digits and dots are allowed so
+/// that the labels the disassembler prints, like `label_7` or the macro local `loop.0`, can be
+/// assembled again.
+fn is_name_char(c: char) -> bool {
+    c == '_' || c == '.' || c.is_alphanumeric()
+}
+
 // Tests
 #[cfg(test)]
 mod tests {
@@ -221,21 +405,21 @@ mod tests {
         let mut lexer = Lexer::new(source);
         lexer.tokenize();
 
-        assert_eq!(lexer.errors.len(), 0);
+        assert_eq!(lexer.diagnostics.len(), 0);
 
         assert_eq!(
             *lexer.get_tokens(),
             vec![
-                Token::Opcode((Opcode::LOAD, 1, 5)),
-                Token::RegisterNum((0, 1, 8)),
-                Token::IntegerOperand((500, 1, 13)),
-                Token::Opcode((Opcode::LOAD, 2, 5)),
-                Token::RegisterNum((1, 2, 8)),
-                Token::IntegerOperand((100, 2, 13)),
-                Token::Opcode((Opcode::ADD, 3, 4)),
-                Token::RegisterNum((0, 3, 7)),
-                Token::RegisterNum((1, 3, 10)),
-                Token::RegisterNum((2, 3, 12)),
+                Token::Opcode((Opcode::LOAD, Span::on_line(1, 1, 5))),
+                Token::RegisterNum((0, Span::on_line(1, 6, 8))),
+                Token::IntegerOperand((500, Span::on_line(1, 9, 13))),
+                Token::Opcode((Opcode::LOAD, Span::on_line(2, 1, 5))),
+                Token::RegisterNum((1, Span::on_line(2, 6, 8))),
+                Token::IntegerOperand((100, Span::on_line(2, 9, 13))),
+                Token::Opcode((Opcode::ADD, Span::on_line(3, 1, 4))),
+                Token::RegisterNum((0, Span::on_line(3, 5, 7))),
+                Token::RegisterNum((1, Span::on_line(3, 8, 10))),
+                Token::RegisterNum((2, Span::on_line(3, 11, 13))),
             ]
         );
     }
@@ -246,11 +430,14 @@ mod tests {
         let mut lexer = Lexer::new(source);
         lexer.tokenize();
 
-        assert_eq!(lexer.errors.len(), 0);
+        assert_eq!(lexer.diagnostics.len(), 0);
 
         assert_eq!(
             *lexer.get_tokens(),
-            vec![Token::LabelDeclaration((String::from("test_label"), 1, 11))]
+            vec![Token::LabelDeclaration((
+                String::from("test_label"),
+                Span::on_line(1, 1, 11)
+            ))]
         );
     }
 
@@ -260,11 +447,34 @@ mod tests {
         let mut lexer = Lexer::new(source);
         lexer.tokenize();
 
-        assert_eq!(lexer.errors.len(), 0);
+        assert_eq!(lexer.diagnostics.len(), 0);
 
         assert_eq!(
             *lexer.get_tokens(),
-            vec![Token::LabelUsage((String::from("test_label"), 1, 11))]
+            vec![Token::LabelUsage((
+                String::from("test_label"),
+                Span::on_line(1, 1, 12)
+            ))]
+        );
+    }
+
+    #[test]
+    fn test_label_names() {
+        let source = "label_7: loop.0: CALL @label_7 JMP @loop.0";
+        let mut lexer = Lexer::new(source);
+        lexer.tokenize();
+
+        assert_eq!(lexer.diagnostics.len(), 0);
+        assert_eq!(
+            *lexer.get_tokens(),
+            vec![
+                Token::LabelDeclaration((String::from("label_7"), Span::on_line(1, 1, 8))),
+                Token::LabelDeclaration((String::from("loop.0"), Span::on_line(1, 10, 16))),
+                Token::Opcode((Opcode::CALL, Span::on_line(1, 18, 22))),
+                Token::LabelUsage((String::from("label_7"), Span::on_line(1, 23, 31))),
+                Token::Opcode((Opcode::JMP, Span::on_line(1, 32, 35))),
+                Token::LabelUsage((String::from("loop.0"), Span::on_line(1, 36, 43))),
+            ]
         );
     }
 
@@ -274,11 +484,233 @@ mod tests {
         let mut lexer = Lexer::new(source);
         lexer.tokenize();
 
-        assert_eq!(lexer.errors.len(), 0);
+        assert_eq!(lexer.diagnostics.len(), 0);
 
         assert_eq!(
             *lexer.get_tokens(),
-            vec![Token::Directive((String::from("some_directive"), 1, 15))]
+            vec![Token::Directive((
+                String::from("some_directive"),
+                Span::on_line(1, 1, 16)
+            ))]
+        );
+    }
+
+    #[test]
+    fn test_floats() {
+        let source = "LOADF $f1 #-2.5\nLOADF $f2 #1.0e3\n#1e20 #1e-7 #-inf";
+        let mut lexer = Lexer::new(source);
+        lexer.tokenize();
+
+        assert_eq!(lexer.diagnostics.len(), 0);
+
+        assert_eq!(
+            *lexer.get_tokens(),
+            vec![
+                Token::Opcode((Opcode::LOADF, Span::on_line(1, 1, 6))),
+                Token::FloatRegisterNum((1, Span::on_line(1, 7, 10))),
+                Token::FloatOperand((-2.5, Span::on_line(1, 11, 16))),
+                Token::Opcode((Opcode::LOADF, Span::on_line(2, 1, 6))),
+                Token::FloatRegisterNum((2, Span::on_line(2, 7, 10))),
+                Token::FloatOperand((1000.0, Span::on_line(2, 11, 17))),
+                Token::FloatOperand((1e20, Span::on_line(3, 1, 6))),
+                Token::FloatOperand((1e-7, Span::on_line(3, 7, 12))),
+                Token::FloatOperand((f64::NEG_INFINITY, Span::on_line(3, 13, 18))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_string_literal() {
+        let source = ".asciiz \"say \\\"hi\\\"\\n\"";
+        let mut lexer = Lexer::new(source);
+        lexer.tokenize();
+
+        assert_eq!(lexer.diagnostics.len(), 0);
+
+        assert_eq!(
+            *lexer.get_tokens(),
+            vec![
+                Token::Directive((String::from("asciiz"), Span::on_line(1, 1, 8))),
+                Token::StringLiteral((String::from("say \"hi\"\n"), Span::on_line(1, 9, 23))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_string() {
+        let mut lexer = Lexer::new(".asciiz \"oops");
+        lexer.tokenize();
+
+        assert_eq!(
+            lexer.diagnostics,
+            vec![Diagnostic::error(
+                Code::UnterminatedString,
+                "unterminated string",
+                Span::on_line(1, 9, 14)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_whitespace_and_invalid_characters() {
+        let mut lexer = Lexer::new("");
+        lexer.tokenize();
+        assert!(lexer.get_tokens().is_empty());
+
+        let mut lexer = Lexer::new("  HLT\n\t~ \"a\\q\"");
+        lexer.tokenize();
+        assert_eq!(
+            *lexer.get_tokens(),
+            vec![
+                Token::Opcode((Opcode::HLT, Span::on_line(1, 3, 6))),
+                Token::StringLiteral((String::from("a"), Span::on_line(2, 4, 9))),
+            ]
+        );
+        assert_eq!(
+            lexer.diagnostics,
+            vec![
+                Diagnostic::error(
+                    Code::InvalidCharacter,
+                    "invalid character",
+                    Span::on_line(2, 2, 3)
+                ),
+                Diagnostic::error(
+                    Code::UnknownEscape,
+                    "unknown escape sequence",
+                    Span::on_line(2, 6, 8)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comments_and_commas() {
+        let mut lexer = Lexer::new("; setup\nADD $0, $1,$2 ; sum\nHLT;done");
+        lexer.tokenize();
+
+        assert_eq!(lexer.diagnostics.len(), 0);
+        assert_eq!(
+            *lexer.get_tokens(),
+            vec![
+                Token::Opcode((Opcode::ADD, Span::on_line(2, 1, 4))),
+                Token::RegisterNum((0, Span::on_line(2, 5, 7))),
+                Token::RegisterNum((1, Span::on_line(2, 9, 11))),
+                Token::RegisterNum((2, Span::on_line(2, 12, 14))),
+                Token::Opcode((Opcode::HLT, Span::on_line(3, 1, 4))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_integer_literals() {
+        let mut lexer = Lexer::new("#0xFF #-0x10 #0b1010 #1_000 #0xFFFF_FFFF #'A' #'\\n' #1_0.5");
+        lexer.tokenize();
+
+        assert_eq!(lexer.diagnostics.len(), 0);
+        assert_eq!(
+            *lexer.get_tokens(),
+            vec![
+                Token::IntegerOperand((255, Span::on_line(1, 1, 6))),
+                Token::IntegerOperand((-16, Span::on_line(1, 7, 13))),
+                Token::IntegerOperand((10, Span::on_line(1, 14, 21))),
+                Token::IntegerOperand((1000, Span::on_line(1, 22, 28))),
+                Token::IntegerOperand((-1, Span::on_line(1, 29, 41))),
+                Token::IntegerOperand((65, Span::on_line(1, 42, 46))),
+                Token::IntegerOperand((10, Span::on_line(1, 47, 52))),
+                Token::FloatOperand((10.5, Span::on_line(1, 53, 59))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_literals() {
+        let mut lexer = Lexer::new("#0x #0b12 #3000000000 #'ab' #'A");
+        lexer.tokenize();
+
+        let diagnostics: Vec<(Code, Span)> = lexer
+            .diagnostics
+            .iter()
+            .map(|d| (d.code.unwrap(), d.span))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                (Code::InvalidNumber, Span::on_line(1, 1, 4)),
+                (Code::InvalidNumber, Span::on_line(1, 5, 10)),
+                (Code::InvalidNumber, Span::on_line(1, 11, 22)),
+                (Code::InvalidNumber, Span::on_line(1, 23, 28)),
+                (Code::UnterminatedString, Span::on_line(1, 29, 32)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_non_ascii() {
+        let mut lexer = Lexer::new(".asciiz \"héllo\" ; ünïcode\nñ: HLT");
+        lexer.tokenize();
+
+        assert_eq!(lexer.diagnostics.len(), 0);
+        assert_eq!(
+            *lexer.get_tokens(),
+            vec![
+                Token::Directive((String::from("asciiz"), Span::on_line(1, 1, 8))),
+                Token::StringLiteral((String::from("héllo"), Span::on_line(1, 9, 16))),
+                Token::LabelDeclaration((String::from("ñ"), Span::on_line(2, 1, 2))),
+                Token::Opcode((Opcode::HLT, Span::on_line(2, 4, 7))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_large_input() {
+        // Tokenizing used to take quadratic time, which made this take minutes.
+        let lines = 50_000;
+        let source = "loop: LOAD $0, #0x1F ; comment\nJMP $0\n".repeat(lines / 2);
+        let mut lexer = Lexer::new(&source);
+        lexer.tokenize();
+
+        assert_eq!(lexer.diagnostics.len(), 0);
+        assert_eq!(lexer.get_tokens().len(), lines / 2 * 6);
+        assert_eq!(
+            lexer.get_tokens().last(),
+            Some(&Token::RegisterNum((0, Span::on_line(lines, 5, 7))))
+        );
+    }
+
+    /// Measures tokenizing a large generated file. Run it with
+    /// `cargo test --release -- --ignored --nocapture bench_tokenize`.
+    #[test]
+    #[ignore]
+    fn bench_tokenize() {
+        use std::time::Instant;
+
+        // Three lines and ten tokens per repeat.
+        let repeats = 300_000;
+        let lines = repeats * 3;
+        let source =
+            "loop: LOAD $0, #0x1F ; comment\nADDF $f0 $f1 $f2\nCALL @loop\n".repeat(repeats);
+        let runs = 5;
+        let best = (0..runs)
+            .map(|_| {
+                let start = Instant::now();
+                let mut lexer = Lexer::new(&source);
+                lexer.tokenize();
+                let elapsed = start.elapsed();
+                assert_eq!(lexer.get_tokens().len(), repeats * 10);
+                elapsed
+            })
+            .min()
+            .unwrap();
+
+        let seconds = best.as_secs_f64();
+        println!(
+            "tokenized {} lines ({:.1} MB) in {:?}: {:.0} lines/s, {:.1} MB/s (best of {})",
+            lines,
+            source.len() as f64 / 1e6,
+            best,
+            lines as f64 / seconds,
+            source.len() as f64 / 1e6 / seconds,
+            runs
         );
     }
 }
@@ -0,0 +1,537 @@
+use super::diagnostic::{Code, Diagnostic, Span};
+use super::lexer::{Lexer, Token};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How deeply macros and includes may expand inside each other.
+pub const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// A file read by `.include`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceFile {
+    pub name: String,
+    pub source: String,
+}
+
+/// One call of a macro.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expansion {
+    pub name: String,
+    /// The macro name and arguments at the call site.
+    pub call: Span,
+}
+
+/// A macro defined with `.macro name params… .endm`.
+#[derive(Clone, Debug)]
+struct Macro {
+    /// The name in the definition.
+    span: Span,
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// Expands macro calls and `.include` directives between the lexer and the compiler.
+///
+/// A macro is defined by `.macro` followed by its name and parameter names on the same line,
+/// then its body up to `.endm`. In the body, `%name` stands for an argument, and labels
+/// declared in the body are renamed for every call so that the macro can be called more than
+/// once. A call is the macro name followed by its arguments on the same line. Macros must be
+/// defined before they are called.
+///
+/// `.include "path"` is replaced by the tokens of the file at `path`, relative to the file that
+/// includes it.
+pub struct Expander {
+    /// The file being assembled, which includes are relative to.
+    filename: String,
+    macros: HashMap<String, Macro>,
+    /// Files read by `.include`. Spans in the `n`th file have `file` set to `n + 1`.
+    pub included: Vec<SourceFile>,
+    pub expansions: Vec<Expansion>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Paths of the files being included, to detect files that include themselves.
+    include_stack: Vec<PathBuf>,
+    output: Vec<Token>,
+}
+
+impl Expander {
+    pub fn new(filename: &str) -> Self {
+        Expander {
+            filename: String::from(filename),
+            macros: HashMap::new(),
+            included: vec![],
+            expansions: vec![],
+            diagnostics: vec![],
+            include_stack: vec![],
+            output: vec![],
+        }
+    }
+
+    /// Returns `tokens` with macros and includes expanded.
+    pub fn expand(&mut self, tokens: &[Token]) -> Vec<Token> {
+        self.output.clear();
+        self.process(tokens, &lines(tokens), 0);
+        std::mem::take(&mut self.output)
+    }
+
+    /// Returns notes pointing at the call sites of the macro expansions that produced `span`,
+    /// innermost first.
+    pub fn expansion_notes(&self, span: Span) -> Vec<Diagnostic> {
+        let mut notes = vec![];
+        let mut expansion = span.expansion;
+        while let Some(i) = expansion {
+            let call = &self.expansions[i];
+            notes.push(Diagnostic::note(
+                &format!("in this expansion of macro '{}'", call.name),
+                call.call,
+            ));
+            expansion = call.call.expansion;
+        }
+        notes
+    }
+
+    /// Expands `tokens` to the output. `lines[i]` is the line that `tokens[i]` is written on,
+    /// and `depth` is the number of macros and includes that the tokens are in.
+    fn process(&mut self, tokens: &[Token], lines: &[usize], depth: usize) {
+        let mut i = 0;
+        while i < tokens.len() {
+            match &tokens[i] {
+                Token::Directive(t) if t.0 == "macro" => i = self.define(tokens, i),
+                Token::Directive(t) if t.0 == "endm" => {
+                    self.add_error(Code::InvalidMacro, ".endm without .macro", t.1);
+                    i += 1;
+                }
+                Token::Directive(t) if t.0 == "include" => {
+                    match tokens.get(i + 1) {
+                        Some(Token::StringLiteral(path)) => {
+                            self.include(&path.0, path.1, depth);
+                            i += 1;
+                        }
+                        _ => self.add_error(Code::ExpectedString, "expected a string", t.1),
+                    }
+                    i += 1;
+                }
+                Token::Identifier(t) if self.macros.contains_key(&t.0) => {
+                    i = self.call(tokens, lines, i, depth);
+                }
+                Token::MacroParameter(t) => {
+                    self.add_error(
+                        Code::UnknownMacroParameter,
+                        "macro parameters can only be used in a macro",
+                        t.1,
+                    );
+                    i += 1;
+                }
+                t => {
+                    self.output.push(t.clone());
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    /// Records the macro defined by the `.macro` directive at `tokens[start]`, and returns the
+    /// index of the token after its `.endm`.
+    fn define(&mut self, tokens: &[Token], start: usize) -> usize {
+        let directive = tokens[start].span();
+        let on_header = |t: &Token| t.span().start.line == directive.start.line;
+        let mut i = start + 1;
+
+        let name = match tokens.get(i) {
+            Some(Token::Identifier(name)) if on_header(&tokens[i]) => {
+                i += 1;
+                Some(name.clone())
+            }
+            _ => {
+                self.add_error(Code::InvalidMacro, "expected a macro name", directive);
+                None
+            }
+        };
+        let mut params: Vec<String> = vec![];
+        while let Some(t) = tokens.get(i).filter(|t| on_header(t)) {
+            match t {
+                Token::Identifier(param) if !params.contains(&param.0) => {
+                    params.push(param.0.clone());
+                }
+                t => self.add_error(Code::InvalidMacro, "expected a parameter name", t.span()),
+            }
+            i += 1;
+        }
+
+        let body_start = i;
+        loop {
+            match tokens.get(i) {
+                None => {
+                    self.add_error(
+                        Code::UnterminatedMacro,
+                        "macro definition without .endm",
+                        directive,
+                    );
+                    return i;
+                }
+                Some(Token::Directive(t)) if t.0 == "endm" => break,
+                Some(Token::Directive(t)) if t.0 == "macro" => {
+                    self.add_error(
+                        Code::InvalidMacro,
+                        "macros cannot be defined inside macros",
+                        t.1,
+                    );
+                }
+                Some(Token::MacroParameter(t)) if !params.contains(&t.0) => {
+                    self.add_error(
+                        Code::UnknownMacroParameter,
+                        &format!("unknown macro parameter '{}'", t.0),
+                        t.1,
+                    );
+                }
+                Some(_) => {}
+            }
+            i += 1;
+        }
+
+        if let Some((name, span)) = name {
+            if let Some(first) = self.macros.get(&name) {
+                let first = first.span;
+                self.add_error(
+                    Code::InvalidMacro,
+                    &format!("macro '{}' is already defined", name),
+                    span,
+                );
+                self.diagnostics
+                    .push(Diagnostic::note("first defined here", first));
+            } else {
+                let body = tokens[body_start..i].to_vec();
+                self.macros.insert(name, Macro { span, params, body });
+            }
+        }
+        i + 1
+    }
+
+    /// Expands the call of the macro named by `tokens[start]`, and returns the index of the
+    /// token after its arguments.
+    fn call(&mut self, tokens: &[Token], lines: &[usize], start: usize, depth: usize) -> usize {
+        let name = match &tokens[start] {
+            Token::Identifier(name) => name.0.clone(),
+            _ => unreachable!("macro calls start with an identifier"),
+        };
+        let name_span = tokens[start].span();
+        // Arguments substituted from a parameter keep the span of their own call, so the
+        // arguments end where the line they are written on in `tokens` does.
+        let args: Vec<Token> = tokens[start + 1..]
+            .iter()
+            .zip(&lines[start + 1..])
+            .take_while(|(t, line)| {
+                **line == lines[start]
+                    && matches!(
+                        t,
+                        Token::RegisterNum(_)
+                            | Token::FloatRegisterNum(_)
+                            | Token::IntegerOperand(_)
+                            | Token::FloatOperand(_)
+                            | Token::LabelUsage(_)
+                            | Token::StringLiteral(_)
+                    )
+            })
+            .map(|(t, _)| t.clone())
+            .collect();
+        let end = start + 1 + args.len();
+        let mut call = name_span;
+        if let Some(last) = args.last() {
+            call.end = last.span().end;
+        }
+
+        let definition = self.macros[&name].clone();
+        if args.len() != definition.params.len() {
+            self.add_error(
+                Code::MacroArguments,
+                &format!(
+                    "macro '{}' takes {} argument(s) but {} were given",
+                    name,
+                    definition.params.len(),
+                    args.len()
+                ),
+                call,
+            );
+            self.diagnostics
+                .push(Diagnostic::note("macro defined here", definition.span));
+            return end;
+        }
+        if depth >= MAX_EXPANSION_DEPTH {
+            self.add_error(
+                Code::ExpansionTooDeep,
+                &format!("macro '{}' expands too deeply", name),
+                call,
+            );
+            return end;
+        }
+
+        let id = self.expansions.len();
+        self.expansions.push(Expansion {
+            name: name.clone(),
+            call,
+        });
+        let locals: Vec<&String> = definition
+            .body
+            .iter()
+            .filter_map(|t| match t {
+                Token::LabelDeclaration(label) => Some(&label.0),
+                _ => None,
+            })
+            .collect();
+        // Local labels get the number of the expansion, so a source label of the same name is
+        // reported as a duplicate.
+        let local = |label: &String| {
+            if locals.contains(&label) {
+                format!("{}.{}", label, id)
+            } else {
+                label.clone()
+            }
+        };
+
+        let mut expanded = vec![];
+        let mut expanded_lines = vec![];
+        for t in &definition.body {
+            let line = t.span().start.line;
+            let mut t = match t {
+                Token::MacroParameter(param) => {
+                    let i = definition.params.iter().position(|p| *p == param.0);
+                    // Unknown parameters were reported with the definition.
+                    match i {
+                        Some(i) => {
+                            expanded.push(args[i].clone());
+                            expanded_lines.push(line);
+                            continue;
+                        }
+                        None => continue,
+                    }
+                }
+                Token::LabelDeclaration(label) => {
+                    Token::LabelDeclaration((local(&label.0), label.1))
+                }
+                Token::LabelUsage(label) => Token::LabelUsage((local(&label.0), label.1)),
+                t => t.clone(),
+            };
+            t.span_mut().expansion = Some(id);
+            expanded.push(t);
+            expanded_lines.push(line);
+        }
+        self.process(&expanded, &expanded_lines, depth + 1);
+        end
+    }
+
+    /// Expands the file at `path`, relative to the file that `span` is in.
+    fn include(&mut self, path: &str, span: Span, depth: usize) {
+        let including = match span.file {
+            0 => self.filename.as_str(),
+            n => self.included[n - 1].name.as_str(),
+        };
+        let path = Path::new(including)
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(path);
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if self.include_stack.contains(&canonical) || depth >= MAX_EXPANSION_DEPTH {
+            self.add_error(
+                Code::ExpansionTooDeep,
+                &format!("'{}' includes itself", path.display()),
+                span,
+            );
+            return;
+        }
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(err) => {
+                self.add_error(
+                    Code::IncludeFailed,
+                    &format!("cannot read '{}': {}", path.display(), err),
+                    span,
+                );
+                return;
+            }
+        };
+
+        let file = self.included.len() + 1;
+        let mut lexer = Lexer::new(&source);
+        lexer.tokenize();
+        for mut diagnostic in lexer.diagnostics.drain(..) {
+            diagnostic.span.file = file;
+            self.diagnostics.push(diagnostic);
+        }
+        let mut tokens = lexer.get_tokens().clone();
+        for t in &mut tokens {
+            t.span_mut().file = file;
+        }
+        self.included.push(SourceFile {
+            name: path.display().to_string(),
+            source,
+        });
+
+        self.include_stack.push(canonical);
+        self.process(&tokens, &lines(&tokens), depth + 1);
+        self.include_stack.pop();
+    }
+
+    fn add_error(&mut self, code: Code, msg: &str, span: Span) {
+        self.diagnostics.push(Diagnostic::error(code, msg, span));
+    }
+}
+
+/// Returns the line that each of `tokens` is written on.
+fn lines(tokens: &[Token]) -> Vec<usize> {
+    tokens.iter().map(|t| t.span().start.line).collect()
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_expand_macros() {
+        // Count the register up to the limit with a loop local to every call.
+        let source = String::from(
+            ".macro count_to reg limit
+                 LOAD $31 %limit
+                 LOAD $30 @loop
+             loop: INC %reg
+                 LT %reg $31
+                 JEQ $30
+             .endm
+             count_to $0 #3
+             count_to $1, #5
+             HLT",
+        );
+        let mut assembler = Assembler::new("<test>", &source);
+        let program = assembler.compile().unwrap();
+        assert_eq!(program.symbols.symbol_value("loop.0"), Some(8));
+        assert_eq!(program.symbols.symbol_value("loop.1"), Some(23));
+
+        let mut vm = VM::new();
+        vm.load_program(&program);
+        vm.run().unwrap();
+        assert_eq!(vm.get_registers()[0], 3);
+        assert_eq!(vm.get_registers()[1], 5);
+    }
+
+    #[test]
+    fn test_nested_macro_arguments() {
+        // The argument of `set_twice` is on another line than the calls of `set`.
+        let source = String::from(
+            ".macro set reg value
+                 LOAD %reg %value
+             .endm
+             .macro set_inc r
+                 set %r #7
+                 INC %r
+             .endm
+             set_inc $1
+             HLT",
+        );
+        let mut assembler = Assembler::new("<test>", &source);
+        let program = assembler.compile().unwrap();
+
+        let mut vm = VM::new();
+        vm.load_program(&program);
+        vm.run().unwrap();
+        assert_eq!(vm.get_registers()[1], 8);
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let source = String::from(
+            ".macro jump target\nLOAD $0 %target\nJMP $0\n.endm\njump @nowhere\njump\n.macro bad\nINC %x\n.endm",
+        );
+        let mut assembler = Assembler::new("<test>", &source);
+        let diagnostics = assembler.compile().unwrap_err();
+        let found: Vec<(Option<Code>, &str, Span)> = diagnostics
+            .iter()
+            .map(|d| (d.code, d.message.as_str(), d.span))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    Some(Code::MacroArguments),
+                    "macro 'jump' takes 1 argument(s) but 0 were given",
+                    Span::on_line(6, 1, 5)
+                ),
+                (None, "macro defined here", Span::on_line(1, 8, 12)),
+                (
+                    Some(Code::UnknownMacroParameter),
+                    "unknown macro parameter 'x'",
+                    Span::on_line(8, 5, 7)
+                ),
+                (
+                    Some(Code::UndefinedLabel),
+                    "undefined label 'nowhere'",
+                    Span::on_line(5, 6, 14)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expansion_notes() {
+        let source = String::from(".macro twice\nINC $0\nINC @nowhere\n.endm\n\ntwice\ntwice");
+        let mut assembler = Assembler::new("<test>", &source);
+        let diagnostics = assembler.compile().unwrap_err();
+
+        // The error points at the definition, and the note at the call.
+        let mut expanded = Span::on_line(3, 5, 13);
+        expanded.expansion = Some(1);
+        let call = Span::on_line(7, 1, 6);
+        assert_eq!(diagnostics.len(), 4);
+        assert_eq!(diagnostics[2].span, expanded);
+        assert_eq!(
+            diagnostics[3],
+            Diagnostic::note("in this expansion of macro 'twice'", call)
+        );
+        assert_eq!(
+            assembler.render(&diagnostics[3..]),
+            "note: in this expansion of macro 'twice'\n --> <test>:7:1\n  |\n7 | twice\n  | ^^^^^\n"
+        );
+
+        // Macros that call themselves stop expanding.
+        let source = String::from(".macro forever\nforever\n.endm\nforever");
+        let mut assembler = Assembler::new("<test>", &source);
+        let diagnostics = assembler.compile().unwrap_err();
+        assert_eq!(diagnostics[0].code, Some(Code::ExpansionTooDeep));
+        assert_eq!(diagnostics.len(), 1 + MAX_EXPANSION_DEPTH);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("fvm_include_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("lib.fasm"),
+            ".macro set reg\nLOAD %reg #7\n.endm\nINC $9",
+        )
+        .unwrap();
+        fs::write(dir.join("self.fasm"), ".include \"self.fasm\"").unwrap();
+        let filename = dir.join("main.fasm").display().to_string();
+
+        let source = String::from(".include \"lib.fasm\"\nset $1\nHLT");
+        let mut assembler = Assembler::new(&filename, &source);
+        let program = assembler.compile().unwrap();
+        assert_eq!(program.code, vec![13, 9, 0, 1, 0, 7, 5]);
+
+        let source = String::from(".include \"self.fasm\"\n.include \"missing.fasm\"");
+        let mut assembler = Assembler::new(&filename, &source);
+        let diagnostics = assembler.compile().unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let codes: Vec<Option<Code>> = diagnostics.iter().map(|d| d.code).collect();
+        assert_eq!(
+            codes,
+            vec![Some(Code::ExpansionTooDeep), Some(Code::IncludeFailed)]
+        );
+        // The recursive include is reported in the included file.
+        assert_eq!(diagnostics[0].span.file, 1);
+        assert!(assembler
+            .render(&diagnostics[..1])
+            .contains("self.fasm:1:10\n  |\n1 | .include \"self.fasm\"\n"));
+    }
+}
@@ -1,82 +1,393 @@
 pub mod compiler;
-pub mod error;
+pub mod diagnostic;
 pub mod lexer;
+pub mod macros;
 pub mod symbol_table;
 
 use self::compiler::Compiler;
+use self::diagnostic::{Code, Diagnostic};
 use self::lexer::{Lexer, Token};
+use self::macros::{Expander, SourceFile};
 use self::symbol_table::{Symbol, SymbolTable, SymbolType};
+use crate::program::Program;
+use std::collections::HashMap;
+use std::iter;
 
 pub enum AssemblerPhase {
     First,
     Second,
 }
 
+/// The section that instructions and data directives are emitted into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Section {
+    Code,
+    Data,
+}
+
 pub struct Assembler<'a> {
     pub phase: AssemblerPhase,
     pub symbols: SymbolTable,
+    /// Errors, warnings and notes about the source, in the order they were found.
+    pub diagnostics: Vec<Diagnostic>,
+    /// Files read by `.include`, which diagnostics may point into.
+    pub included: Vec<SourceFile>,
     filename: &'a str,
     source: &'a String,
 }
 
 impl<'a> Assembler<'a> {
-    pub fn new<'b>(filename: &'a str, source: &'a String) -> Self {
+    pub fn new(filename: &'a str, source: &'a String) -> Self {
         Assembler {
             phase: AssemblerPhase::First,
             symbols: SymbolTable::new(),
+            diagnostics: vec![],
+            included: vec![],
             filename,
             source,
         }
     }
 
-    pub fn compile(&mut self) -> Vec<u8> {
+    /// Assembles the source. On failure, returns all of the diagnostics, including warnings and
+    /// notes, and no program. Warnings about a program that assembles are left in
+    /// `diagnostics`.
+    pub fn compile(&mut self) -> Result<Program, Vec<Diagnostic>> {
         // tokenize to tokens
         let mut lexer = Lexer::new(self.source.as_str());
         lexer.tokenize();
-        if lexer.errors.len() > 0 {
-            for err in &lexer.errors {
-                println!("{}", err.format(self.filename));
-            }
-        }
-        let tokens = lexer.get_tokens();
+        self.diagnostics.append(&mut lexer.diagnostics);
+
+        // expand macros and includes
+        let mut expander = Expander::new(self.filename);
+        let tokens = expander.expand(lexer.get_tokens());
+        self.diagnostics.append(&mut expander.diagnostics);
 
         // first phase
-        self.process_first_phase(tokens);
+        self.process_first_phase(&tokens);
 
         // second phase
-        self.process_second_phase(tokens)
+        let program = self.process_second_phase(&tokens);
+
+        // Diagnostics about expanded code point at the macro calls too.
+        self.diagnostics = self
+            .diagnostics
+            .drain(..)
+            .flat_map(|d| {
+                let notes = expander.expansion_notes(d.span);
+                iter::once(d).chain(notes)
+            })
+            .collect();
+        self.included = expander.included;
+        if self.has_errors() {
+            return Err(self.diagnostics.clone());
+        }
+        Ok(program)
+    }
+
+    /// Renders `diagnostics` against the source files they point into.
+    pub fn render(&self, diagnostics: &[Diagnostic]) -> String {
+        diagnostics
+            .iter()
+            .map(|d| {
+                let (filename, source) = self.file(d.span.file);
+                d.render(filename, source)
+            })
+            .collect()
+    }
+
+    /// Formats `diagnostics` as a JSON array, naming the files they point into.
+    pub fn to_json(&self, diagnostics: &[Diagnostic]) -> String {
+        let objects: Vec<String> = diagnostics
+            .iter()
+            .map(|d| d.to_json(self.file(d.span.file).0))
+            .collect();
+        format!("[{}]", objects.join(","))
+    }
+
+    /// Returns the name and source of a file that spans point into.
+    fn file(&self, file: usize) -> (&str, &str) {
+        match file {
+            0 => (self.filename, self.source),
+            n => (&self.included[n - 1].name, &self.included[n - 1].source),
+        }
+    }
+
+    /// Returns whether any of the diagnostics is an error.
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(Diagnostic::is_error)
     }
 
     fn process_first_phase(&mut self, p: &Vec<Token>) {
         self.extract_labels(p);
         self.phase = AssemblerPhase::Second;
     }
-    fn process_second_phase(&mut self, p: &Vec<Token>) -> Vec<u8> {
+    fn process_second_phase(&mut self, p: &Vec<Token>) -> Program {
         // compile to binary
         let mut compiler = Compiler::new(p, &self.symbols);
         compiler.compile_all();
-        if compiler.errors.len() > 0 {
-            for err in &compiler.errors {
-                println!("{}", err.format(self.filename));
-            }
-        }
+        self.diagnostics.append(&mut compiler.diagnostics);
 
-        compiler.get_compiled_program()
+        let mut program = Program::new(compiler.get_compiled_program());
+        program.ro_data = compiler.get_data();
+        program.symbols = self.symbols.clone();
+        program
     }
 
+    /// Records the byte offset of every label declaration in the symbol table. Labels in the
+    /// `.data` section are offsets into the read-only data rather than the code.
     fn extract_labels(&mut self, p: &Vec<Token>) {
-        let mut c = 0;
+        let mut section = Section::Code;
+        let mut offsets = [0u32; 2];
+        // The data directive whose operands follow, if any.
+        let mut directive = None;
+        // Where each label was first declared, for duplicates to point at.
+        let mut declared = HashMap::new();
         for i in p {
-            if i.is_label() {
-                match i.get_label_name() {
-                    Some(name) => {
-                        let symbol = Symbol::new(name, SymbolType::Label, c);
+            let c = &mut offsets[section as usize];
+            match i {
+                Token::LabelDeclaration(t) => {
+                    if let Some(&first) = declared.get(&t.0) {
+                        self.diagnostics.push(Diagnostic::error(
+                            Code::DuplicateLabel,
+                            format!("duplicate label '{}'", t.0).as_str(),
+                            t.1,
+                        ));
+                        self.diagnostics
+                            .push(Diagnostic::note("first declared here", first));
+                    } else {
+                        declared.insert(t.0.clone(), t.1);
+                        let symbol_type = match section {
+                            Section::Code => SymbolType::Label,
+                            Section::Data => SymbolType::Data,
+                        };
+                        let symbol = Symbol::new(t.0.clone(), symbol_type, *c);
                         self.symbols.add_symbol(symbol);
                     }
-                    None => {}
-                };
+                    directive = None;
+                }
+                Token::Opcode(t) => {
+                    *c += t.0.info().map_or(0, |info| info.encoded_len() as u32);
+                    directive = None;
+                }
+                Token::Identifier(_) => directive = None,
+                Token::Directive(t) => {
+                    match t.0.as_str() {
+                        "code" => section = Section::Code,
+                        "data" => section = Section::Data,
+                        _ => {}
+                    }
+                    directive = Some(t.0.as_str());
+                }
+                Token::StringLiteral(t) if directive == Some("asciiz") => {
+                    *c += t.0.len() as u32 + 1;
+                    directive = None;
+                }
+                Token::IntegerOperand(_) if directive == Some("byte") => *c += 1,
+                Token::IntegerOperand(_) if directive == Some("word") => *c += 4,
+                _ => {}
             }
-            c += 4;
         }
     }
 }
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::diagnostic::Span;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_label_offsets() {
+        let source = String::from("INC $0\nstart: LOAD $0 #1\nend: HLT");
+        let mut assembler = Assembler::new("<test>", &source);
+        assembler.compile().unwrap();
+
+        assert!(assembler.diagnostics.is_empty());
+        assert_eq!(assembler.symbols.symbol_value("start"), Some(2));
+        assert_eq!(assembler.symbols.symbol_value("end"), Some(6));
+    }
+
+    #[test]
+    fn test_label_usage() {
+        let source = String::from("CALL @func\nHLT\nfunc: LOAD $0 @func\nRET");
+        let mut assembler = Assembler::new("<test>", &source);
+        let program = assembler.compile().unwrap();
+
+        assert_eq!(program.code, vec![15, 0, 4, 5, 0, 0, 0, 4, 16]);
+        assert_eq!(program.symbols.symbol_value("func"), Some(4));
+    }
+
+    #[test]
+    fn test_undefined_label() {
+        let source = String::from("CALL @nowhere");
+        let mut assembler = Assembler::new("<test>", &source);
+        assert_eq!(
+            assembler.compile().unwrap_err(),
+            vec![Diagnostic::error(
+                Code::UndefinedLabel,
+                "undefined label 'nowhere'",
+                Span::on_line(1, 6, 14)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_conditional_loop() {
+        // Count register 0 up to 5 with LT driving JEQ.
+        let source = String::from("LOAD $1 #5\nLOAD $2 @loop\nloop: INC $0\nLT $0 $1\nJEQ $2\nHLT");
+        let mut assembler = Assembler::new("<test>", &source);
+        let program = assembler.compile().unwrap();
+
+        let mut vm = VM::new();
+        vm.load_program(&program);
+        vm.run().unwrap();
+        assert_eq!(vm.get_registers()[0], 5);
+    }
+
+    #[test]
+    fn test_duplicate_label() {
+        let source = String::from("a: HLT\na: HLT");
+        let mut assembler = Assembler::new("<test>", &source);
+        assert_eq!(
+            assembler.compile().unwrap_err(),
+            vec![
+                Diagnostic::error(
+                    Code::DuplicateLabel,
+                    "duplicate label 'a'",
+                    Span::on_line(2, 1, 2)
+                ),
+                Diagnostic::note("first declared here", Span::on_line(1, 1, 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_section() {
+        let source = String::from(
+            ".data\nmsg: .asciiz \"hi\"\nnums: .byte #1 #2\nmask: .word #-1\n.code\nLOAD $0 @nums\nend: HLT",
+        );
+        let mut assembler = Assembler::new("<test>", &source);
+        let program = assembler.compile().unwrap();
+
+        assert_eq!(program.code, vec![0, 0, 0, 3, 5]);
+        assert_eq!(program.ro_data, vec![104, 105, 0, 1, 2, 255, 255, 255, 255]);
+        assert_eq!(
+            program.symbols.symbols(),
+            &vec![
+                Symbol::new(String::from("msg"), SymbolType::Data, 0),
+                Symbol::new(String::from("nums"), SymbolType::Data, 3),
+                Symbol::new(String::from("mask"), SymbolType::Data, 5),
+                Symbol::new(String::from("end"), SymbolType::Label, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_errors() {
+        let source = String::from(".data\nHLT\n.asciiz #1\n.byte #256\n.text");
+        let mut assembler = Assembler::new("<test>", &source);
+        assert_eq!(
+            assembler.compile().unwrap_err(),
+            vec![
+                Diagnostic::error(
+                    Code::InstructionInData,
+                    "instructions must be in the .code section",
+                    Span::on_line(2, 1, 4)
+                ),
+                Diagnostic::error(
+                    Code::ExpectedString,
+                    "expected a string",
+                    Span::on_line(3, 1, 8)
+                ),
+                Diagnostic::error(
+                    Code::ExpectedOpcode,
+                    "expected an opcode",
+                    Span::on_line(3, 9, 11)
+                ),
+                Diagnostic::error(
+                    Code::ValueOutOfRange,
+                    "should be u8",
+                    Span::on_line(4, 7, 11)
+                ),
+                Diagnostic::error(
+                    Code::UnknownDirective,
+                    "unknown directive",
+                    Span::on_line(5, 1, 6)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_in_code() {
+        let source = String::from("HLT\n.asciiz \"x\"\n.byte #256");
+        let mut assembler = Assembler::new("<test>", &source);
+        assert_eq!(
+            assembler.compile().unwrap_err(),
+            vec![
+                Diagnostic::error(
+                    Code::DataInCode,
+                    "data directives must be in the .data section",
+                    Span::on_line(2, 1, 8)
+                ),
+                Diagnostic::error(
+                    Code::DataInCode,
+                    "data directives must be in the .data section",
+                    Span::on_line(3, 1, 6)
+                ),
+                Diagnostic::error(
+                    Code::ValueOutOfRange,
+                    "should be u8",
+                    Span::on_line(3, 7, 11)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_label_operand() {
+        let source =
+            String::from(".data\npad: .byte #0\nmsg: .asciiz \"hi\"\n.code\nPRTS @msg\nHLT");
+        let mut assembler = Assembler::new("<test>", &source);
+        let program = assembler.compile().unwrap();
+
+        assert_eq!(program.code, vec![43, 0, 1, 5]);
+    }
+
+    #[test]
+    fn test_warnings() {
+        let source = String::from("CALL #-1\nHLT");
+        let mut assembler = Assembler::new("<test>", &source);
+        let program = assembler.compile().unwrap();
+        assert_eq!(program.code, vec![15, 255, 255, 5]);
+        assert_eq!(assembler.diagnostics.len(), 1);
+        assert!(!assembler.diagnostics[0].is_error());
+
+        // A failed assembly returns the warnings too.
+        let source = String::from("CALL #-1\nFOO");
+        let mut assembler = Assembler::new("<test>", &source);
+        let diagnostics = assembler.compile().unwrap_err();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            assembler.render(&diagnostics[1..]),
+            "error[E0005]: unknown opcode\n --> <test>:2:1\n  |\n2 | FOO\n  | ^^^\n"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let source = String::from("FOO\nBAR");
+        let mut assembler = Assembler::new("a.fasm", &source);
+        let diagnostics = assembler.compile().unwrap_err();
+        assert_eq!(
+            assembler.to_json(&diagnostics),
+            format!(
+                "[{},{}]",
+                diagnostics[0].to_json("a.fasm"),
+                diagnostics[1].to_json("a.fasm")
+            )
+        );
+        assert_eq!(assembler.to_json(&[]), "[]");
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Symbol {
     name: String,
     offset: u32,
@@ -13,18 +13,39 @@ impl Symbol {
             offset,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SymbolType {
+    /// An offset into the code section.
     Label,
+    /// An offset into the read-only data section.
+    Data,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct SymbolTable {
     symbols: Vec<Symbol>,
 }
 
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SymbolTable {
     pub fn new() -> SymbolTable {
         SymbolTable { symbols: vec![] }
@@ -34,6 +55,14 @@ impl SymbolTable {
         self.symbols.push(s);
     }
 
+    pub fn symbols(&self) -> &Vec<Symbol> {
+        &self.symbols
+    }
+
+    pub fn symbol(&self, s: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == s)
+    }
+
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
         for symbol in &self.symbols {
             if symbol.name == s {
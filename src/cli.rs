@@ -0,0 +1,444 @@
+use fvm::assembler::Assembler;
+use fvm::disassembler;
+use fvm::program::Program;
+use fvm::repl::REPL;
+use fvm::vm::trace::{self, TraceBuffer, TraceWriter};
+use fvm::vm::VM;
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Exit code for a successful command.
+pub const EXIT_SUCCESS: i32 = 0;
+/// Exit code when the VM stops with an error or a file cannot be read or written.
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code for invalid command-line arguments.
+pub const EXIT_USAGE: i32 = 2;
+/// Exit code when the source does not assemble.
+pub const EXIT_ASSEMBLY: i32 = 3;
+
+const USAGE: &str = "usage:
+    fvm asm <input.fasm> [-o <output.fvmb>] [--json]
+    fvm run <program.fvmb> [--profile | --profile-time]
+    fvm disasm <program.fvmb>
+    fvm trace <program.fvmb> [-o <trace.txt>] [--last <n>]
+    fvm tracediff <trace.txt> <trace.txt>
+    fvm repl [--hex]
+
+exit codes:
+    0  success
+    1  the program failed, the traces differ, or a file cannot be read or written
+    2  invalid arguments
+    3  the source does not assemble";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Assembles a source file into a program file, also printing the diagnostics as JSON to
+    /// stdout if `json` is set.
+    Asm {
+        input: PathBuf,
+        output: PathBuf,
+        json: bool,
+    },
+    /// Runs a program file, printing a profile report at the end if `profile` is set, with the
+    /// time spent per opcode if `time` is set too.
+    Run {
+        path: PathBuf,
+        profile: bool,
+        time: bool,
+    },
+    /// Prints a program file as assembly.
+    Disasm { path: PathBuf },
+    /// Runs a program file, printing every executed instruction to `output` or stdout, or only
+    /// the `last` ones once the program stops.
+    Trace {
+        path: PathBuf,
+        output: Option<PathBuf>,
+        last: Option<usize>,
+    },
+    /// Compares two traces.
+    TraceDiff { left: PathBuf, right: PathBuf },
+    /// Starts the REPL.
+    Repl { hex: bool },
+}
+
+/// Parses the arguments, not including the program name.
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut args = args.iter();
+    let subcommand = match args.next() {
+        Some(subcommand) => subcommand.as_str(),
+        None => return Ok(Command::Repl { hex: false }),
+    };
+
+    match subcommand {
+        "asm" => {
+            let mut input = None;
+            let mut output = None;
+            let mut json = false;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "-o" => match args.next() {
+                        Some(path) => output = Some(PathBuf::from(path)),
+                        None => return Err(String::from("-o requires a path")),
+                    },
+                    "--json" => json = true,
+                    _ if input.is_none() => input = Some(PathBuf::from(arg)),
+                    _ => return Err(format!("unexpected argument '{}'", arg)),
+                }
+            }
+            let input = input.ok_or_else(|| String::from("missing input file"))?;
+            let output = output.unwrap_or_else(|| input.with_extension("fvmb"));
+            Ok(Command::Asm {
+                input,
+                output,
+                json,
+            })
+        }
+        "run" => {
+            let mut path = None;
+            let mut profile = false;
+            let mut time = false;
+            for arg in args {
+                match arg.as_str() {
+                    "--profile" => profile = true,
+                    "--profile-time" => (profile, time) = (true, true),
+                    _ if path.is_none() => path = Some(PathBuf::from(arg)),
+                    _ => return Err(format!("unexpected argument '{}'", arg)),
+                }
+            }
+            let path = path.ok_or_else(|| String::from("missing program file"))?;
+            Ok(Command::Run {
+                path,
+                profile,
+                time,
+            })
+        }
+        "disasm" => match (args.next(), args.next()) {
+            (Some(path), None) => Ok(Command::Disasm {
+                path: PathBuf::from(path),
+            }),
+            (None, _) => Err(String::from("missing program file")),
+            (_, Some(arg)) => Err(format!("unexpected argument '{}'", arg)),
+        },
+        "trace" => {
+            let mut path = None;
+            let mut output = None;
+            let mut last = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "-o" => match args.next() {
+                        Some(file) => output = Some(PathBuf::from(file)),
+                        None => return Err(String::from("-o requires a path")),
+                    },
+                    "--last" => match args.next().map(|n| n.parse()) {
+                        Some(Ok(n)) => last = Some(n),
+                        _ => return Err(String::from("--last requires a number")),
+                    },
+                    _ if path.is_none() => path = Some(PathBuf::from(arg)),
+                    _ => return Err(format!("unexpected argument '{}'", arg)),
+                }
+            }
+            let path = path.ok_or_else(|| String::from("missing program file"))?;
+            Ok(Command::Trace { path, output, last })
+        }
+        "tracediff" => match (args.next(), args.next(), args.next()) {
+            (Some(left), Some(right), None) => Ok(Command::TraceDiff {
+                left: PathBuf::from(left),
+                right: PathBuf::from(right),
+            }),
+            (_, _, Some(arg)) => Err(format!("unexpected argument '{}'", arg)),
+            _ => Err(String::from("missing trace file")),
+        },
+        "repl" => match args.next().map(|arg| arg.as_str()) {
+            None => Ok(Command::Repl { hex: false }),
+            Some("--hex") => Ok(Command::Repl { hex: true }),
+            Some(arg) => Err(format!("unexpected argument '{}'", arg)),
+        },
+        _ => Err(format!("unknown command '{}'", subcommand)),
+    }
+}
+
+/// Runs the command given by the arguments and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let command = match parse_args(args) {
+        Ok(command) => command,
+        Err(msg) => {
+            eprintln!("error: {}\n{}", msg, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    match command {
+        Command::Asm {
+            input,
+            output,
+            json,
+        } => assemble(&input, &output, json),
+        Command::Run {
+            path,
+            profile,
+            time,
+        } => run_program(&path, profile, time),
+        Command::Disasm { path } => disassemble(&path),
+        Command::Trace { path, output, last } => trace_program(&path, output.as_deref(), last),
+        Command::TraceDiff { left, right } => diff_traces(&left, &right),
+        Command::Repl { hex } => {
+            let mut repl = REPL::new();
+            repl.run(hex);
+            EXIT_SUCCESS
+        }
+    }
+}
+
+/// Assembles `input` into `output`. The exit code is `EXIT_ASSEMBLY` if there are errors;
+/// warnings do not count.
+fn assemble(input: &Path, output: &Path, json: bool) -> i32 {
+    let source = match fs::read_to_string(input) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("error: cannot read {}: {}", input.display(), err);
+            return EXIT_FAILURE;
+        }
+    };
+
+    let filename = input.display().to_string();
+    let mut assembler = Assembler::new(&filename, &source);
+    let (program, diagnostics) = match assembler.compile() {
+        Ok(program) => (Some(program), assembler.diagnostics.clone()),
+        Err(diagnostics) => (None, diagnostics),
+    };
+    if json {
+        println!("{}", assembler.to_json(&diagnostics));
+    } else {
+        eprint!("{}", assembler.render(&diagnostics));
+    }
+    let program = match program {
+        Some(program) => program,
+        None => return EXIT_ASSEMBLY,
+    };
+
+    match fs::write(output, program.to_bytes()) {
+        Ok(()) => EXIT_SUCCESS,
+        Err(err) => {
+            eprintln!("error: cannot write {}: {}", output.display(), err);
+            EXIT_FAILURE
+        }
+    }
+}
+
+/// Number of instructions listed in a profile report.
+const PROFILE_TOP: usize = 10;
+
+fn run_program(path: &Path, profile: bool, time: bool) -> i32 {
+    let program = match read_program(path) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+
+    let mut vm = VM::new();
+    vm.load_program(&program);
+    if profile {
+        vm.enable_profiling(time);
+    }
+    let code = execute(&mut vm, &program);
+    if let Some(profile) = vm.take_profile() {
+        eprint!(
+            "{}",
+            profile.report(&program.code, &program.symbols, PROFILE_TOP)
+        );
+    }
+    code
+}
+
+/// Runs the program loaded in `vm`, printing a crash report if it stops with an error.
+fn execute(vm: &mut VM, program: &Program) -> i32 {
+    match vm.run() {
+        Ok(_) => EXIT_SUCCESS,
+        Err(err) => {
+            let (instruction, _) = disassembler::disassemble_instruction(&program.code, err.pc());
+            eprintln!("error: {}\n    at {:04}  {}", err, err.pc(), instruction);
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn trace_program(path: &Path, output: Option<&Path>, last: Option<usize>) -> i32 {
+    let program = match read_program(path) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+    let mut vm = VM::new();
+    vm.load_program(&program);
+
+    if let Some(n) = last {
+        let buffer = Rc::new(RefCell::new(TraceBuffer::new(n)));
+        vm.set_tracer(Box::new(buffer.clone()));
+        let code = execute(&mut vm, &program);
+        for entry in buffer.borrow().entries() {
+            println!("{}", entry);
+        }
+        return code;
+    }
+
+    let writer: Box<dyn Write> = match output {
+        Some(output) => match File::create(output) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(err) => {
+                eprintln!("error: cannot write {}: {}", output.display(), err);
+                return EXIT_FAILURE;
+            }
+        },
+        None => Box::new(io::stdout()),
+    };
+    let writer = Rc::new(RefCell::new(TraceWriter::new(writer)));
+    vm.set_tracer(Box::new(writer.clone()));
+    let code = execute(&mut vm, &program);
+    let result = writer.borrow_mut().finish();
+    if let Err(err) = result {
+        eprintln!("error: cannot write trace: {}", err);
+        return EXIT_FAILURE;
+    }
+    code
+}
+
+/// Compares two traces. Like `diff`, the exit code is 1 if they differ.
+fn diff_traces(left: &Path, right: &Path) -> i32 {
+    let read = |path: &Path| {
+        fs::read_to_string(path).map_err(|err| {
+            eprintln!("error: cannot read {}: {}", path.display(), err);
+            EXIT_FAILURE
+        })
+    };
+    let (left_trace, right_trace) = match (read(left), read(right)) {
+        (Ok(left), Ok(right)) => (left, right),
+        _ => return EXIT_FAILURE,
+    };
+
+    match trace::diff(&left_trace, &right_trace) {
+        None => {
+            println!("traces are identical");
+            EXIT_SUCCESS
+        }
+        Some(diff) => {
+            println!("traces differ at line {}:", diff.line);
+            let end = String::from("<end of trace>");
+            println!("< {}", diff.left.unwrap_or_else(|| end.clone()));
+            println!("> {}", diff.right.unwrap_or(end));
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn disassemble(path: &Path) -> i32 {
+    let program = match read_program(path) {
+        Ok(program) => program,
+        Err(code) => return code,
+    };
+
+    println!("; entry point: {}", program.entry_point);
+    print!(
+        "{}",
+        disassembler::disassemble(&program.code, &program.symbols)
+    );
+    if !program.ro_data.is_empty() {
+        println!("; data ({} bytes):", program.ro_data.len());
+        print_hex(&program.ro_data);
+    }
+    EXIT_SUCCESS
+}
+
+/// Prints bytes in rows of 8, each row prefixed with the offset of its first byte.
+fn print_hex(bytes: &[u8]) {
+    for (i, row) in bytes.chunks(8).enumerate() {
+        let row: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+        println!(";   {:04}  {}", i * 8, row.join(" "));
+    }
+}
+
+/// Reads and validates a program file, returning the exit code to use on failure.
+fn read_program(path: &Path) -> Result<Program, i32> {
+    let bytes = fs::read(path).map_err(|err| {
+        eprintln!("error: cannot read {}: {}", path.display(), err);
+        EXIT_FAILURE
+    })?;
+    Program::from_bytes(&bytes).map_err(|err| {
+        eprintln!("error: {}: {}", path.display(), err);
+        EXIT_FAILURE
+    })
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_asm() {
+        assert_eq!(
+            parse_args(&args(&["asm", "in.fasm", "-o", "out.fvmb"])),
+            Ok(Command::Asm {
+                input: PathBuf::from("in.fasm"),
+                output: PathBuf::from("out.fvmb"),
+                json: false
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["asm", "--json", "in.fasm"])),
+            Ok(Command::Asm {
+                input: PathBuf::from("in.fasm"),
+                output: PathBuf::from("in.fvmb"),
+                json: true
+            })
+        );
+        assert!(parse_args(&args(&["asm"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_run_and_repl() {
+        assert_eq!(
+            parse_args(&args(&["run", "out.fvmb"])),
+            Ok(Command::Run {
+                path: PathBuf::from("out.fvmb"),
+                profile: false,
+                time: false
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["run", "--profile-time", "out.fvmb"])),
+            Ok(Command::Run {
+                path: PathBuf::from("out.fvmb"),
+                profile: true,
+                time: true
+            })
+        );
+        assert_eq!(parse_args(&[]), Ok(Command::Repl { hex: false }));
+        assert_eq!(
+            parse_args(&args(&["repl", "--hex"])),
+            Ok(Command::Repl { hex: true })
+        );
+        assert!(parse_args(&args(&["run", "a", "b"])).is_err());
+        assert_eq!(
+            parse_args(&args(&["trace", "out.fvmb", "--last", "10"])),
+            Ok(Command::Trace {
+                path: PathBuf::from("out.fvmb"),
+                output: None,
+                last: Some(10)
+            })
+        );
+        assert_eq!(
+            parse_args(&args(&["tracediff", "a.txt", "b.txt"])),
+            Ok(Command::TraceDiff {
+                left: PathBuf::from("a.txt"),
+                right: PathBuf::from("b.txt")
+            })
+        );
+        assert!(parse_args(&args(&["trace", "out.fvmb", "--last", "x"])).is_err());
+        assert!(parse_args(&args(&["build"])).is_err());
+    }
+}
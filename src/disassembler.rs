@@ -0,0 +1,249 @@
+use crate::assembler::symbol_table::{SymbolTable, SymbolType};
+use crate::instruction::{DecodeError, Instruction, Opcode, OperandKind};
+use std::collections::BTreeMap;
+
+/// An instruction decoded at an offset. Illegal and truncated instructions are kept as a single
+/// byte without an instruction, and offsets past the end of the code as no bytes.
+#[derive(Clone, Debug, PartialEq)]
+struct Decoded {
+    offset: usize,
+    len: usize,
+    instruction: Option<Instruction>,
+}
+
+fn decode(code: &[u8], offset: usize) -> Decoded {
+    match code.get(offset..).map(Instruction::decode) {
+        Some(Ok(instruction)) => Decoded {
+            offset,
+            len: instruction.encoded_len(),
+            instruction: Some(instruction),
+        },
+        None | Some(Err(DecodeError::Empty)) => Decoded {
+            offset,
+            len: 0,
+            instruction: None,
+        },
+        Some(Err(_)) => Decoded {
+            offset,
+            len: 1,
+            instruction: None,
+        },
+    }
+}
+
+fn decode_all(code: &[u8]) -> Vec<Decoded> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < code.len() {
+        let instruction = decode(code, offset);
+        offset += instruction.len;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+/// Returns the offsets of the `LOAD` instructions whose number is used as a jump target, together
+/// with every address that is the target of a jump or `CALL`.
+fn find_jump_targets(code: &[u8], instructions: &[Decoded]) -> (Vec<usize>, Vec<usize>) {
+    let mut address_loads = vec![];
+    let mut targets = vec![];
+    // The last `LOAD` into each register that has not been overwritten since.
+    let mut loaded: [Option<(usize, u64)>; 256] = [None; 256];
+
+    for decoded in instructions {
+        let instruction = match decoded.instruction {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        let register = |i| instruction.operand(i) as u8 as usize;
+        match instruction.opcode {
+            Opcode::LOAD => loaded[register(0)] = Some((decoded.offset, instruction.operand(1))),
+            Opcode::JMP | Opcode::JEQ | Opcode::JNEQ => {
+                if let Some((load_offset, target)) = loaded[register(0)] {
+                    if target as usize <= code.len() {
+                        address_loads.push(load_offset);
+                        targets.push(target as usize);
+                    }
+                }
+            }
+            Opcode::CALL => targets.push(instruction.operand(0) as usize),
+            Opcode::CALLN => loaded[0] = None,
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => loaded[register(2)] = None,
+            Opcode::INC
+            | Opcode::DEC
+            | Opcode::POP
+            | Opcode::FTOI
+            | Opcode::LOADB
+            | Opcode::LOADW => loaded[register(0)] = None,
+            _ => {}
+        }
+    }
+    (address_loads, targets)
+}
+
+/// Formats an instruction as assembly, using `labels` for addresses and `data_labels` for
+/// offsets into the read-only data.
+fn format_instruction(
+    decoded: &Decoded,
+    code: &[u8],
+    labels: &BTreeMap<usize, String>,
+    data_labels: &BTreeMap<usize, String>,
+    address_loads: &[usize],
+) -> String {
+    let byte = || match code.get(decoded.offset) {
+        Some(byte) => format!(".byte #{}", byte),
+        None => String::new(),
+    };
+    let instruction = match decoded.instruction {
+        Some(instruction) => instruction,
+        None => return byte(),
+    };
+    let info = match instruction.opcode.info() {
+        Some(info) => info,
+        None => return byte(),
+    };
+
+    let mut text = String::from(info.mnemonic);
+    for (i, kind) in info.operands.iter().enumerate() {
+        let value = instruction.operand(i);
+        let label = match kind {
+            OperandKind::DataOffset => data_labels.get(&(value as usize)),
+            _ => labels.get(&(value as usize)),
+        };
+        match (kind, label) {
+            (OperandKind::Register, _) => text.push_str(&format!(" ${}", value)),
+            (OperandKind::FloatRegister, _) => text.push_str(&format!(" $f{}", value)),
+            // Debug formatting, e.g. `1.5`, `1e20` or `NaN`, is read back exactly by the lexer.
+            (OperandKind::Float, _) => {
+                text.push_str(&format!(" #{:?}", instruction.float_operand(i)))
+            }
+            (OperandKind::Number, Some(label)) if address_loads.contains(&decoded.offset) => {
+                text.push_str(&format!(" @{}", label))
+            }
+            (OperandKind::Address | OperandKind::DataOffset, Some(label)) => {
+                text.push_str(&format!(" @{}", label))
+            }
+            (OperandKind::Number | OperandKind::Address | OperandKind::DataOffset, _) => {
+                text.push_str(&format!(" #{}", value))
+            }
+        }
+    }
+    text
+}
+
+/// Disassembles the instruction at `offset`, returning its text and length in bytes. Past the
+/// end of the code, the text is empty and the length 0.
+pub fn disassemble_instruction(code: &[u8], offset: usize) -> (String, usize) {
+    let decoded = decode(code, offset);
+    let text = format_instruction(&decoded, code, &BTreeMap::new(), &BTreeMap::new(), &[]);
+    (text, decoded.len)
+}
+
+/// Disassembles a whole program. Each instruction is prefixed with its byte offset, and labels
+/// are taken from `symbols` or synthesized for jump and call targets.
+pub fn disassemble(code: &[u8], symbols: &SymbolTable) -> String {
+    let instructions = decode_all(code);
+    let (address_loads, targets) = find_jump_targets(code, &instructions);
+
+    let mut labels = BTreeMap::new();
+    let mut data_labels = BTreeMap::new();
+    for symbol in symbols.symbols() {
+        let offset = symbol.offset() as usize;
+        match symbol.symbol_type() {
+            SymbolType::Label => labels.insert(offset, symbol.name().to_string()),
+            SymbolType::Data => data_labels.insert(offset, symbol.name().to_string()),
+        };
+    }
+    for target in targets {
+        labels
+            .entry(target)
+            .or_insert_with(|| format!("label_{}", target));
+    }
+
+    let mut text = String::new();
+    for instruction in &instructions {
+        if let Some(label) = labels.get(&instruction.offset) {
+            text.push_str(&format!("{}:\n", label));
+        }
+        text.push_str(&format!(
+            "    {:04}  {}\n",
+            instruction.offset,
+            format_instruction(instruction, code, &labels, &data_labels, &address_loads)
+        ));
+    }
+    if let Some(label) = labels.get(&code.len()) {
+        text.push_str(&format!("{}:\n", label));
+    }
+    text
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::symbol_table::Symbol;
+
+    #[test]
+    fn test_disassemble_instruction() {
+        let code = vec![0, 0, 1, 244, 1, 0, 1, 2, 9, 0, 1, 5];
+        assert_eq!(
+            disassemble_instruction(&code, 0),
+            (String::from("LOAD $0 #500"), 4)
+        );
+        assert_eq!(
+            disassemble_instruction(&code, 4),
+            (String::from("ADD $0 $1 $2"), 4)
+        );
+        assert_eq!(
+            disassemble_instruction(&code, 8),
+            (String::from("EQ $0 $1"), 3)
+        );
+        assert_eq!(disassemble_instruction(&code, 11), (String::from("HLT"), 1));
+        assert_eq!(disassemble_instruction(&code, 12), (String::new(), 0));
+        assert_eq!(disassemble_instruction(&code, 20), (String::new(), 0));
+    }
+
+    #[test]
+    fn test_illegal_and_truncated() {
+        let code = vec![200, 0, 0];
+        assert_eq!(
+            disassemble(&code, &SymbolTable::new()),
+            "    0000  .byte #200\n    0001  .byte #0\n    0002  .byte #0\n"
+        );
+    }
+
+    #[test]
+    fn test_synthesized_labels() {
+        // Load 7 to register 0, jump to it, call 10, and halt.
+        let code = vec![0, 0, 0, 7, 6, 0, 5, 15, 0, 10, 16];
+        let expected = "    0000  LOAD $0 @label_7
+    0004  JMP $0
+    0006  HLT
+label_7:
+    0007  CALL @label_10
+label_10:
+    0010  RET
+";
+        assert_eq!(disassemble(&code, &SymbolTable::new()), expected);
+    }
+
+    #[test]
+    fn test_symbol_labels() {
+        let code = vec![15, 0, 4, 5, 16];
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new(String::from("func"), SymbolType::Label, 4));
+        let expected = "    0000  CALL @func\n    0003  HLT\nfunc:\n    0004  RET\n";
+        assert_eq!(disassemble(&code, &symbols), expected);
+    }
+
+    #[test]
+    fn test_data_labels() {
+        let code = vec![43, 0, 3, 5];
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new(String::from("msg"), SymbolType::Data, 3));
+        assert_eq!(
+            disassemble(&code, &symbols),
+            "    0000  PRTS @msg\n    0003  HLT\n"
+        );
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum Opcode {
     HLT = 5,   // halt
@@ -12,43 +12,279 @@ pub enum Opcode {
     JMPF = 7, // relative forward jump
     JMPB = 8, // relative backward jump
     EQ = 9,
-    JEQ = 10,  // jump if equal
-    JNEQ = 11, // jump if not equal
+    JEQ = 10,  // jump if the last comparison was true
+    JNEQ = 11, // jump if the last comparison was false
     ALOC = 12,
     INC = 13,
     DEC = 14,
+    CALL = 15,
+    RET = 16,
+    PUSH = 17,
+    POP = 18,
+    LOADF = 19,
+    ADDF = 20,
+    SUBF = 21,
+    MULF = 22,
+    DIVF = 23,
+    EQF = 24,
+    NEQF = 25,
+    GTF = 26,
+    GTEF = 27,
+    LTF = 28,
+    LTEF = 29,
+    ITOF = 30, // integer to float
+    FTOI = 31, // float to integer
+    NEQ = 32,
+    GT = 33,
+    GTE = 34,
+    LT = 35,
+    LTE = 36,
+    LOADB = 37,  // load byte from the heap
+    STOREB = 38, // store byte to the heap
+    LOADW = 39,  // load word from the heap
+    STOREW = 40, // store word to the heap
+    FREE = 41,
+    PRTI = 42,  // print integer
+    PRTS = 43,  // print string from read-only data
+    PRTH = 44,  // print bytes from the heap
+    CALLN = 45, // call a native function
 }
 
+/// The kinds of operands an instruction can take.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    /// One byte holding a register number, written `$0`.
+    Register,
+    /// Two bytes holding a big-endian number, written `#500` or `@label`.
+    Number,
+    /// Two bytes holding a big-endian address in the program, written `@label` or `#12`.
+    Address,
+    /// Two bytes holding a big-endian offset into the read-only data, written `@label` or `#0`.
+    DataOffset,
+    /// One byte holding a float register number, written `$f0`.
+    FloatRegister,
+    /// Eight bytes holding a big-endian `f64`, written `#1.5`.
+    Float,
+}
+
+impl OperandKind {
+    /// Returns the number of bytes the operand is encoded in.
+    pub fn encoded_len(self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister => 1,
+            OperandKind::Number | OperandKind::Address | OperandKind::DataOffset => 2,
+            OperandKind::Float => 8,
+        }
+    }
+}
+
+/// Describes how an instruction is written and encoded.
 #[derive(Debug, PartialEq)]
-pub struct Instruction {
+pub struct InstructionInfo {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    pub operands: &'static [OperandKind],
+}
+
+impl InstructionInfo {
+    /// Returns the number of bytes the instruction is encoded in, including the opcode.
+    pub fn encoded_len(&self) -> usize {
+        1 + self
+            .operands
+            .iter()
+            .map(|kind| kind.encoded_len())
+            .sum::<usize>()
+    }
+}
+
+/// The most operands any instruction takes.
+pub const MAX_OPERANDS: usize = 3;
+
+const NONE: &[OperandKind] = &[];
+const REG: &[OperandKind] = &[OperandKind::Register];
+const REG_REG: &[OperandKind] = &[OperandKind::Register, OperandKind::Register];
+const REG_REG_REG: &[OperandKind] = &[
+    OperandKind::Register,
+    OperandKind::Register,
+    OperandKind::Register,
+];
+const REG_NUM: &[OperandKind] = &[OperandKind::Register, OperandKind::Number];
+const NUM: &[OperandKind] = &[OperandKind::Number];
+const ADDR: &[OperandKind] = &[OperandKind::Address];
+const DATA: &[OperandKind] = &[OperandKind::DataOffset];
+const FREG_FREG: &[OperandKind] = &[OperandKind::FloatRegister, OperandKind::FloatRegister];
+const FREG_FREG_FREG: &[OperandKind] = &[
+    OperandKind::FloatRegister,
+    OperandKind::FloatRegister,
+    OperandKind::FloatRegister,
+];
+const FREG_FLOAT: &[OperandKind] = &[OperandKind::FloatRegister, OperandKind::Float];
+const FREG_REG: &[OperandKind] = &[OperandKind::FloatRegister, OperandKind::Register];
+const REG_FREG: &[OperandKind] = &[OperandKind::Register, OperandKind::FloatRegister];
+
+const fn info(
     opcode: Opcode,
+    mnemonic: &'static str,
+    operands: &'static [OperandKind],
+) -> InstructionInfo {
+    InstructionInfo {
+        opcode,
+        mnemonic,
+        operands,
+    }
 }
 
-impl Instruction {
-    pub fn new(opcode: Opcode) -> Self {
-        Instruction { opcode }
+/// Every legal instruction, indexed by opcode byte.
+pub const INSTRUCTIONS: &[InstructionInfo] = &[
+    info(Opcode::LOAD, "LOAD", REG_NUM),
+    info(Opcode::ADD, "ADD", REG_REG_REG),
+    info(Opcode::SUB, "SUB", REG_REG_REG),
+    info(Opcode::MUL, "MUL", REG_REG_REG),
+    info(Opcode::DIV, "DIV", REG_REG_REG),
+    info(Opcode::HLT, "HLT", NONE),
+    info(Opcode::JMP, "JMP", REG),
+    info(Opcode::JMPF, "JMPF", REG),
+    info(Opcode::JMPB, "JMPB", REG),
+    info(Opcode::EQ, "EQ", REG_REG),
+    info(Opcode::JEQ, "JEQ", REG),
+    info(Opcode::JNEQ, "JNEQ", REG),
+    info(Opcode::ALOC, "ALOC", REG),
+    info(Opcode::INC, "INC", REG),
+    info(Opcode::DEC, "DEC", REG),
+    info(Opcode::CALL, "CALL", ADDR),
+    info(Opcode::RET, "RET", NONE),
+    info(Opcode::PUSH, "PUSH", REG),
+    info(Opcode::POP, "POP", REG),
+    info(Opcode::LOADF, "LOADF", FREG_FLOAT),
+    info(Opcode::ADDF, "ADDF", FREG_FREG_FREG),
+    info(Opcode::SUBF, "SUBF", FREG_FREG_FREG),
+    info(Opcode::MULF, "MULF", FREG_FREG_FREG),
+    info(Opcode::DIVF, "DIVF", FREG_FREG_FREG),
+    info(Opcode::EQF, "EQF", FREG_FREG),
+    info(Opcode::NEQF, "NEQF", FREG_FREG),
+    info(Opcode::GTF, "GTF", FREG_FREG),
+    info(Opcode::GTEF, "GTEF", FREG_FREG),
+    info(Opcode::LTF, "LTF", FREG_FREG),
+    info(Opcode::LTEF, "LTEF", FREG_FREG),
+    info(Opcode::ITOF, "ITOF", FREG_REG),
+    info(Opcode::FTOI, "FTOI", REG_FREG),
+    info(Opcode::NEQ, "NEQ", REG_REG),
+    info(Opcode::GT, "GT", REG_REG),
+    info(Opcode::GTE, "GTE", REG_REG),
+    info(Opcode::LT, "LT", REG_REG),
+    info(Opcode::LTE, "LTE", REG_REG),
+    info(Opcode::LOADB, "LOADB", REG_REG),
+    info(Opcode::STOREB, "STOREB", REG_REG),
+    info(Opcode::LOADW, "LOADW", REG_REG),
+    info(Opcode::STOREW, "STOREW", REG_REG),
+    info(Opcode::FREE, "FREE", REG),
+    info(Opcode::PRTI, "PRTI", REG),
+    info(Opcode::PRTS, "PRTS", DATA),
+    info(Opcode::PRTH, "PRTH", REG_REG),
+    info(Opcode::CALLN, "CALLN", NUM),
+];
+
+impl Opcode {
+    /// Returns the encoding of the opcode, or `None` for `IGL`.
+    pub fn info(self) -> Option<&'static InstructionInfo> {
+        INSTRUCTIONS.get(self as usize)
+    }
+
+    /// Looks up an opcode by its mnemonic, ignoring case.
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        INSTRUCTIONS
+            .iter()
+            .find(|info| info.mnemonic.eq_ignore_ascii_case(mnemonic))
+            .map(|info| info.opcode)
     }
 }
 
 impl From<u8> for Opcode {
     fn from(v: u8) -> Self {
-        match v {
-            0 => Opcode::LOAD,
-            1 => Opcode::ADD,
-            2 => Opcode::SUB,
-            3 => Opcode::MUL,
-            4 => Opcode::DIV,
-            5 => Opcode::HLT,
-            6 => Opcode::JMP,
-            7 => Opcode::JMPF,
-            8 => Opcode::JMPB,
-            9 => Opcode::EQ,
-            10 => Opcode::JEQ,
-            11 => Opcode::JNEQ,
-            12 => Opcode::ALOC,
-            13 => Opcode::INC,
-            14 => Opcode::DEC,
-            _ => Opcode::IGL,
+        match INSTRUCTIONS.get(usize::from(v)) {
+            Some(info) => info.opcode,
+            None => Opcode::IGL,
+        }
+    }
+}
+
+/// Errors raised while decoding an instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    /// There are no bytes to decode.
+    Empty,
+    /// The byte is not a known opcode.
+    IllegalOpcode(u8),
+    /// The bytes ended in the middle of the instruction's operands.
+    Truncated(Opcode),
+}
+
+/// A decoded instruction with its operand values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Instruction {
+    pub opcode: Opcode,
+    operands: [u64; MAX_OPERANDS],
+}
+
+impl Instruction {
+    pub fn new(opcode: Opcode) -> Self {
+        Instruction {
+            opcode,
+            operands: [0; MAX_OPERANDS],
+        }
+    }
+
+    /// Creates an instruction with operand values in the order of `InstructionInfo::operands`.
+    pub fn with_operands(opcode: Opcode, operands: &[u64]) -> Self {
+        let mut instruction = Instruction::new(opcode);
+        instruction.operands[..operands.len()].copy_from_slice(operands);
+        instruction
+    }
+
+    /// Returns the value of the operand at `i`.
+    pub fn operand(&self, i: usize) -> u64 {
+        self.operands[i]
+    }
+
+    /// Returns the value of the `Float` operand at `i`.
+    pub fn float_operand(&self, i: usize) -> f64 {
+        f64::from_bits(self.operands[i])
+    }
+
+    /// Returns the number of bytes the instruction is encoded in.
+    pub fn encoded_len(&self) -> usize {
+        self.opcode.info().map_or(1, |info| info.encoded_len())
+    }
+
+    /// Decodes the instruction at the start of `bytes`.
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let byte = *bytes.first().ok_or(DecodeError::Empty)?;
+        let info = Opcode::from(byte)
+            .info()
+            .ok_or(DecodeError::IllegalOpcode(byte))?;
+
+        let mut instruction = Instruction::new(info.opcode);
+        let mut c = 1;
+        for (i, kind) in info.operands.iter().enumerate() {
+            let operand = bytes
+                .get(c..c + kind.encoded_len())
+                .ok_or(DecodeError::Truncated(info.opcode))?;
+            instruction.operands[i] = operand
+                .iter()
+                .fold(0, |value, &b| (value << 8) | u64::from(b));
+            c += kind.encoded_len();
+        }
+        Ok(instruction)
+    }
+
+    /// Appends the encoded instruction to `bytes`.
+    pub fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.push(self.opcode as u8);
+        if let Some(info) = self.opcode.info() {
+            for (i, kind) in info.operands.iter().enumerate() {
+                let value = self.operands[i].to_be_bytes();
+                bytes.extend_from_slice(&value[value.len() - kind.encoded_len()..]);
+            }
         }
     }
 }
@@ -57,10 +293,92 @@ impl From<u8> for Opcode {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::Assembler;
+    use crate::disassembler;
 
     #[test]
     fn test_create_instruction() {
         let instruction = Instruction::new(Opcode::HLT);
         assert_eq!(instruction.opcode, Opcode::HLT);
     }
+
+    #[test]
+    fn test_table_is_indexed_by_opcode() {
+        for (i, info) in INSTRUCTIONS.iter().enumerate() {
+            assert_eq!(info.opcode as usize, i);
+            assert_eq!(Opcode::from(i as u8), info.opcode);
+            assert_eq!(Opcode::from_mnemonic(info.mnemonic), Some(info.opcode));
+            assert!(info.operands.len() <= MAX_OPERANDS);
+        }
+        assert_eq!(Opcode::from(INSTRUCTIONS.len() as u8), Opcode::IGL);
+        assert_eq!(Opcode::IGL.info(), None);
+    }
+
+    #[test]
+    fn test_round_trip_every_opcode() {
+        for info in INSTRUCTIONS {
+            // Write the instruction with distinct operands, e.g. `LOAD $1 #2`.
+            let mut source = String::from(info.mnemonic);
+            let mut operands = vec![];
+            for (i, kind) in info.operands.iter().enumerate() {
+                let value = i as u64 + 1;
+                match kind {
+                    OperandKind::Register => source.push_str(&format!(" ${}", value)),
+                    OperandKind::FloatRegister => source.push_str(&format!(" $f{}", value)),
+                    OperandKind::Number | OperandKind::Address | OperandKind::DataOffset => {
+                        source.push_str(&format!(" #{}", value))
+                    }
+                    OperandKind::Float => source.push_str(&format!(" #{}.5", value)),
+                }
+                operands.push(match kind {
+                    OperandKind::Float => (value as f64 + 0.5).to_bits(),
+                    _ => value,
+                });
+            }
+            let expected = Instruction::with_operands(info.opcode, &operands);
+
+            let mut encoded = vec![];
+            expected.encode(&mut encoded);
+            assert_eq!(encoded.len(), info.encoded_len());
+            assert_eq!(Instruction::decode(&encoded), Ok(expected));
+            assert_eq!(Instruction::decode(&[]), Err(DecodeError::Empty));
+            if !info.operands.is_empty() {
+                assert_eq!(
+                    Instruction::decode(&encoded[..encoded.len() - 1]),
+                    Err(DecodeError::Truncated(info.opcode))
+                );
+            }
+
+            let mut assembler = Assembler::new("<test>", &source);
+            let program = assembler.compile().unwrap();
+            assert_eq!(assembler.diagnostics.len(), 0, "{}", source);
+            assert_eq!(program.code, encoded, "{}", source);
+
+            let (text, len) = disassembler::disassemble_instruction(&program.code, 0);
+            assert_eq!(text, source);
+            assert_eq!(len, info.encoded_len());
+
+            // Floats that print with an exponent or as a word must read back the same.
+            if !info.operands.contains(&OperandKind::Float) {
+                continue;
+            }
+            for value in [1e20, 1e-7, -2.5e-300, f64::MAX, f64::NEG_INFINITY, f64::NAN] {
+                let operands: Vec<u64> = info
+                    .operands
+                    .iter()
+                    .map(|kind| match kind {
+                        OperandKind::Float => value.to_bits(),
+                        _ => 1,
+                    })
+                    .collect();
+                let mut encoded = vec![];
+                Instruction::with_operands(info.opcode, &operands).encode(&mut encoded);
+                let (text, _) = disassembler::disassemble_instruction(&encoded, 0);
+
+                let mut assembler = Assembler::new("<test>", &text);
+                let program = assembler.compile().unwrap();
+                assert_eq!(program.code, encoded, "{}", text);
+            }
+        }
+    }
 }
@@ -0,0 +1,6 @@
+pub mod assembler;
+pub mod disassembler;
+pub mod instruction;
+pub mod program;
+pub mod repl;
+pub mod vm;
@@ -1,9 +1,6 @@
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod vm;
+mod cli;
 
 fn main() {
-    let mut repl = repl::REPL::new();
-    repl.run(false);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    std::process::exit(cli::run(&args));
 }
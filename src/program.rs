@@ -0,0 +1,265 @@
+use crate::assembler::symbol_table::{Symbol, SymbolTable, SymbolType};
+use std::fmt;
+
+/// Magic bytes every program file starts with.
+pub const MAGIC: [u8; 4] = *b"FVMB";
+/// Version of the program file format written by this build.
+pub const VERSION: u16 = 1;
+/// Size of the header in bytes.
+pub const HEADER_LEN: usize = 24;
+
+/// An assembled program.
+///
+/// The on-disk layout is, with all integers big-endian:
+///
+/// ```text
+/// magic          [u8; 4]  "FVMB"
+/// version        u16
+/// reserved       u16
+/// entry point    u32      offset into the code section
+/// code length    u32
+/// data length    u32
+/// symbol count   u32
+/// code           [u8; code length]
+/// read-only data [u8; data length]
+/// symbols        symbol count times: type u8, offset u32, name length u16, name (UTF-8)
+/// ```
+///
+/// The symbol type is 0 for a code label and 1 for a data label.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub entry_point: u32,
+    pub code: Vec<u8>,
+    pub ro_data: Vec<u8>,
+    pub symbols: SymbolTable,
+}
+
+/// Errors raised while reading a program file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProgramError {
+    /// The file does not start with `MAGIC`.
+    BadMagic,
+    /// The file was written by an incompatible version of the format.
+    UnsupportedVersion(u16),
+    /// The file ended before all of its sections were read.
+    Truncated,
+    /// A symbol has an unknown type or a name that is not valid UTF-8.
+    InvalidSymbol,
+    /// The entry point lies outside of the code section.
+    InvalidEntryPoint(u32),
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgramError::BadMagic => write!(f, "not an fvm program"),
+            ProgramError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported program version {} (expected {})",
+                v, VERSION
+            ),
+            ProgramError::Truncated => write!(f, "program file is truncated"),
+            ProgramError::InvalidSymbol => write!(f, "program file has an invalid symbol"),
+            ProgramError::InvalidEntryPoint(e) => {
+                write!(f, "entry point {} is outside of the code section", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProgramError {}
+
+impl From<Truncated> for ProgramError {
+    fn from(_: Truncated) -> Self {
+        ProgramError::Truncated
+    }
+}
+
+impl Program {
+    pub fn new(code: Vec<u8>) -> Self {
+        Program {
+            entry_point: 0,
+            code,
+            ro_data: vec![],
+            symbols: SymbolTable::new(),
+        }
+    }
+
+    /// Encodes the program in the program file format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.code.len() + self.ro_data.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        bytes.extend_from_slice(&0u16.to_be_bytes());
+        bytes.extend_from_slice(&self.entry_point.to_be_bytes());
+        bytes.extend_from_slice(&(self.code.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.ro_data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.symbols.symbols().len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.code);
+        bytes.extend_from_slice(&self.ro_data);
+        for symbol in self.symbols.symbols() {
+            bytes.push(match symbol.symbol_type() {
+                SymbolType::Label => 0,
+                SymbolType::Data => 1,
+            });
+            bytes.extend_from_slice(&symbol.offset().to_be_bytes());
+            bytes.extend_from_slice(&(symbol.name().len() as u16).to_be_bytes());
+            bytes.extend_from_slice(symbol.name().as_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes a program file, validating its header.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProgramError> {
+        let mut reader = Reader::new(bytes);
+
+        if reader
+            .take(MAGIC.len())
+            .map_err(|_| ProgramError::BadMagic)?
+            != MAGIC
+        {
+            return Err(ProgramError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(ProgramError::UnsupportedVersion(version));
+        }
+        reader.u16()?;
+        let entry_point = reader.u32()?;
+        let code_len = reader.u32()? as usize;
+        let data_len = reader.u32()? as usize;
+        let symbol_count = reader.u32()?;
+
+        let code = reader.take(code_len)?.to_vec();
+        let ro_data = reader.take(data_len)?.to_vec();
+
+        let mut symbols = SymbolTable::new();
+        for _ in 0..symbol_count {
+            let symbol_type = match reader.u8()? {
+                0 => SymbolType::Label,
+                1 => SymbolType::Data,
+                _ => return Err(ProgramError::InvalidSymbol),
+            };
+            let offset = reader.u32()?;
+            let name_len = reader.u16()? as usize;
+            let name = std::str::from_utf8(reader.take(name_len)?)
+                .map_err(|_| ProgramError::InvalidSymbol)?;
+            symbols.add_symbol(Symbol::new(name.to_string(), symbol_type, offset));
+        }
+
+        if entry_point as usize > code.len() {
+            return Err(ProgramError::InvalidEntryPoint(entry_point));
+        }
+
+        Ok(Program {
+            entry_point,
+            code,
+            ro_data,
+            symbols,
+        })
+    }
+}
+
+/// Raised by `Reader` when the bytes end before a value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Truncated;
+
+/// Reads big-endian values from a byte slice.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    c: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, c: 0 }
+    }
+
+    /// Returns whether every byte has been read.
+    pub(crate) fn is_end(&self) -> bool {
+        self.c >= self.bytes.len()
+    }
+
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], Truncated> {
+        let end = self.c.checked_add(n).ok_or(Truncated)?;
+        let bytes = self.bytes.get(self.c..end).ok_or(Truncated)?;
+        self.c = end;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, Truncated> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, Truncated> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, Truncated> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, Truncated> {
+        let b = self.take(8)?;
+        Ok(u64::from_be_bytes([
+            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+        ]))
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_program() -> Program {
+        let mut program = Program::new(vec![0, 0, 1, 244, 5]);
+        program.entry_point = 4;
+        program.ro_data = vec![104, 105, 0];
+        program
+            .symbols
+            .add_symbol(Symbol::new(String::from("end"), SymbolType::Label, 4));
+        program
+            .symbols
+            .add_symbol(Symbol::new(String::from("msg"), SymbolType::Data, 0));
+        program
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let program = test_program();
+        let bytes = program.to_bytes();
+
+        assert_eq!(&bytes[0..4], b"FVMB");
+        assert_eq!(Program::from_bytes(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let mut bytes = test_program().to_bytes();
+        bytes[0] = b'X';
+        assert_eq!(Program::from_bytes(&bytes), Err(ProgramError::BadMagic));
+        assert_eq!(Program::from_bytes(b"FV"), Err(ProgramError::BadMagic));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut bytes = test_program().to_bytes();
+        bytes[5] = 2;
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(ProgramError::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let bytes = test_program().to_bytes();
+        assert_eq!(
+            Program::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ProgramError::Truncated)
+        );
+    }
+}
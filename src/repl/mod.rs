@@ -1,5 +1,9 @@
+use crate::assembler::symbol_table::{SymbolTable, SymbolType};
 use crate::assembler::Assembler;
-use crate::vm::VM;
+use crate::disassembler;
+use crate::vm::error::VmError;
+use crate::vm::{ExitStatus, VM};
+use std::collections::BTreeSet;
 use std::fs::File;
 use std::io::Write;
 use std::io::{self, Read};
@@ -9,75 +13,108 @@ use std::path::Path;
 pub struct REPL {
     command_buffer: Vec<String>,
     vm: VM,
+    /// Labels of the loaded program, used by `.break` and `.program`
+    symbols: SymbolTable,
+    /// Offsets that `.continue` stops at
+    breakpoints: BTreeSet<usize>,
+    /// Source entered so far, which is reassembled as a whole on every entry
+    source: String,
+}
+
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl REPL {
     pub fn new() -> REPL {
-        return REPL {
+        REPL {
             vm: VM::new(),
             command_buffer: vec![],
-        };
+            symbols: SymbolTable::new(),
+            breakpoints: BTreeSet::new(),
+            source: String::new(),
+        }
     }
 
     /// Runs a REPL.
     pub fn run(&mut self, hex: bool) {
         println!("Welcome to Feo!");
+        // Lines entered between `.begin` and `.end`, if in block mode
+        let mut block: Option<Vec<String>> = None;
         loop {
             let mut buffer = String::new();
             let stdin = io::stdin();
 
             // flush stdout as well
-            print!(">> ");
+            print!("{}", if block.is_some() { ".. " } else { ">> " });
             io::stdout().flush().expect("Unable to flush stdout.");
 
-            stdin
+            let read = stdin
                 .read_line(&mut buffer)
                 .expect("Unable to read input line.");
+            if read == 0 {
+                // end of input
+                println!();
+                return;
+            }
             let buffer = buffer.trim();
 
             // store the line given
             self.command_buffer.push(buffer.to_string());
 
-            match buffer {
+            if let Some(lines) = block.as_mut() {
+                if buffer == ".end" {
+                    let code = lines.join("\n");
+                    block = None;
+                    print!("{}", self.enter_source(&code));
+                } else {
+                    lines.push(buffer.to_string());
+                }
+                continue;
+            }
+
+            if let Some(output) = self.debug_command(buffer) {
+                print!("{}", output);
+                continue;
+            }
+
+            let words: Vec<&str> = buffer.split_whitespace().collect();
+            match words.as_slice() {
                 // Exits the REPL.
-                ".quit" | ".exit" => {
+                [".quit" | ".exit"] => {
                     println!("Bye");
                     std::process::exit(0);
                 }
                 // Shows the history of the previous user commands.
-                ".history" => {
+                [".history"] => {
                     for command in &self.command_buffer {
                         println!("{}", command);
                     }
                 }
                 // Lists the instructions currently in VM's program vector.
-                ".program" => {
-                    println!("{:#?}", self.vm.get_program());
+                [".program"] => {
+                    print!(
+                        "{}",
+                        disassembler::disassemble(self.vm.get_program(), &self.symbols)
+                    );
                 }
+                // Shows the source entered so far.
+                [".source"] => print!("{}", self.source),
                 // Lists the registers.
-                ".registers" => {
+                [".registers"] => {
                     println!("{:#?}", self.vm.get_registers());
                 }
-                // Loads file.
-                ".load_file" => {
-                    print!("Enter the path of the file you wish to load: ");
-                    io::stdout().flush().expect("Unable to stdout");
-                    let mut tmp = String::new();
-                    stdin
-                        .read_line(&mut tmp)
-                        .expect("Unable to read input line.");
-                    let tmp = tmp.trim();
-                    let filename = Path::new(&tmp);
-                    let mut f = File::open(Path::new(&filename)).expect("File not found");
-                    let mut contents = String::new();
-                    f.read_to_string(&mut contents)
-                        .expect("Error reading from the file");
-                    let source = buffer.to_string();
-                    let mut assembler = Assembler::new("<input>", &source);
-                    for byte in assembler.compile() {
-                        self.vm.add_byte(byte);
-                    }
+                // Lists the float registers.
+                [".float_registers"] => {
+                    println!("{:#?}", self.vm.get_float_registers());
                 }
+                // Replaces the program with a source file.
+                [".load" | ".load_file", path] => print!("{}", self.load_file(Path::new(path))),
+                [".load" | ".load_file", ..] => println!("usage: .load <path>"),
+                // Starts a block of lines that are entered together.
+                [".begin"] => block = Some(vec![]),
                 _ => {
                     if hex {
                         let results = self.parse_hex(buffer);
@@ -91,18 +128,209 @@ impl REPL {
                                 println!("Unable to decode hex string. Please enter 4 groups of 2 hex characters");
                             }
                         }
-                    } else {
-                        let source = buffer.to_string();
-                        let mut assembler = Assembler::new("<input>", &source);
-                        for byte in assembler.compile() {
-                            self.vm.add_byte(byte);
+                        // Run the instruction.
+                        if let Err(err) = self.vm.run_once() {
+                            print!("{}", self.format_error(&err));
                         }
+                    } else {
+                        print!("{}", self.enter_source(buffer));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Appends `code` to the source entered so far, reassembles it so that labels can be used
+    /// across entries, and runs from the counter until the program stops or reaches a
+    /// breakpoint. Code that does not assemble is discarded, and its diagnostics are returned.
+    fn enter_source(&mut self, code: &str) -> String {
+        let mut source = self.source.clone();
+        source.push_str(code);
+        source.push('\n');
+
+        let mut assembler = Assembler::new("<input>", &source);
+        let program = match assembler.compile() {
+            Ok(program) => program,
+            Err(diagnostics) => return assembler.render(&diagnostics),
+        };
+        let mut output = assembler.render(&assembler.diagnostics);
+        self.source = source;
+        self.symbols = program.symbols.clone();
+        self.vm.update_program(&program);
+
+        match self.run_until_stop() {
+            Ok(ExitStatus::Running) => {
+                output.push_str(&format!("breakpoint\n{}", self.format_pc()));
+            }
+            Ok(_) => {}
+            Err(err) => output.push_str(&self.format_error(&err)),
+        }
+        output
+    }
+
+    /// Replaces the program with the source file at `path`, without running it.
+    fn load_file(&mut self, path: &Path) -> String {
+        let mut source = String::new();
+        if let Err(err) = File::open(path).and_then(|mut f| f.read_to_string(&mut source)) {
+            return format!("cannot read {}: {}\n", path.display(), err);
+        }
+        if !source.ends_with('\n') {
+            source.push('\n');
+        }
+
+        let filename = path.display().to_string();
+        let mut assembler = Assembler::new(&filename, &source);
+        let program = match assembler.compile() {
+            Ok(program) => program,
+            Err(diagnostics) => return assembler.render(&diagnostics),
+        };
+        let warnings = assembler.render(&assembler.diagnostics);
+        self.source = source;
+        self.symbols = program.symbols.clone();
+        self.vm.load_program(&program);
+        format!(
+            "{}loaded {} ({} bytes)\n",
+            warnings,
+            path.display(),
+            program.code.len()
+        )
+    }
+
+    /// Executes instructions until the program stops, or until the counter reaches a breakpoint
+    /// after at least one instruction, in which case the status is `ExitStatus::Running`.
+    fn run_until_stop(&mut self) -> Result<ExitStatus, VmError> {
+        loop {
+            match self.vm.run_once()? {
+                ExitStatus::Running => {
+                    if self.breakpoints.contains(&self.vm.get_pc()) {
+                        return Ok(ExitStatus::Running);
+                    }
+                }
+                status => return Ok(status),
+            }
+        }
+    }
+
+    /// Runs a debugger command and returns its output, or `None` if `line` is not one.
+    fn debug_command(&mut self, line: &str) -> Option<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let output = match words.as_slice() {
+            [".break"] => self
+                .breakpoints
+                .iter()
+                .map(|offset| format!("breakpoint at {:04}\n", offset))
+                .collect(),
+            [".break", target] => match self.parse_target(target) {
+                Some(offset) => {
+                    self.breakpoints.insert(offset);
+                    format!("breakpoint at {:04}\n", offset)
+                }
+                None => format!("unknown offset or label '{}'\n", target),
+            },
+            [".step"] => {
+                let result = self.vm.run_once();
+                self.format_stop(result)
+            }
+            [".continue"] => match self.run_until_stop() {
+                Ok(ExitStatus::Running) => format!("breakpoint\n{}", self.format_pc()),
+                result => self.format_stop(result),
+            },
+            [".pc"] => self.format_pc(),
+            [".heap", start, len] => match (start.parse::<usize>(), len.parse::<usize>()) {
+                (Ok(start), Ok(len)) => self.format_heap(start, len),
+                _ => String::from("usage: .heap <start> <len>\n"),
+            },
+            [".flag"] => format!("{}\n", self.vm.get_equal_flag()),
+            [".profile"] => match self.vm.get_profile() {
+                Some(profile) => profile.report(self.vm.get_program(), &self.symbols, 10),
+                None => String::from("profiling is off, start it with .profile on\n"),
+            },
+            [".profile", "on" | "time"] => {
+                self.vm.enable_profiling(words[1] == "time");
+                String::from("profiling\n")
+            }
+            [".profile", "off"] => match self.vm.take_profile() {
+                Some(profile) => profile.report(self.vm.get_program(), &self.symbols, 10),
+                None => String::new(),
+            },
+            [".set", register, value] => {
+                let register = register
+                    .strip_prefix('$')
+                    .and_then(|register| register.parse::<usize>().ok())
+                    .filter(|register| *register < self.vm.get_registers().len());
+                match (register, value.parse::<i32>()) {
+                    (Some(register), Ok(value)) => {
+                        self.vm.set_register(register, value);
+                        format!("${} = {}\n", register, value)
                     }
-                    // Run the instruction.
-                    self.vm.run_once();
+                    _ => String::from("usage: .set $<register> <value>\n"),
                 }
             }
+            [".break" | ".step" | ".continue" | ".pc" | ".heap" | ".flag" | ".set" | ".profile", ..] => {
+                String::from(
+                    "usage: .break [offset|label], .step, .continue, .pc, .heap <start> <len>, \
+                     .flag, .set $<register> <value>, .profile [on|time|off]\n",
+                )
+            }
+            _ => return None,
+        };
+        Some(output)
+    }
+
+    /// Returns the offset given as a number or as the name of a label.
+    fn parse_target(&self, target: &str) -> Option<usize> {
+        target.parse().ok().or_else(|| {
+            self.symbols
+                .symbols()
+                .iter()
+                .find(|symbol| {
+                    symbol.name() == target && *symbol.symbol_type() == SymbolType::Label
+                })
+                .map(|symbol| symbol.offset() as usize)
+        })
+    }
+
+    /// Formats the instruction at the program counter.
+    fn format_pc(&mut self) -> String {
+        let pc = self.vm.get_pc();
+        if pc >= self.vm.get_program().len() {
+            return format!("    {:04}  <end of program>\n", pc);
+        }
+        let (instruction, _) = disassembler::disassemble_instruction(self.vm.get_program(), pc);
+        format!("    {:04}  {}\n", pc, instruction)
+    }
+
+    /// Formats why execution stopped after `.step` or `.continue`.
+    fn format_stop(&mut self, result: Result<ExitStatus, VmError>) -> String {
+        match result {
+            Ok(ExitStatus::Running) => self.format_pc(),
+            Ok(ExitStatus::Halted) => String::from("halted\n"),
+            Ok(ExitStatus::Finished) => String::from("finished\n"),
+            Ok(ExitStatus::OutOfFuel) => String::from("out of fuel\n"),
+            Err(err) => self.format_error(&err),
+        }
+    }
+
+    /// Formats an error together with the disassembled instruction that faulted.
+    fn format_error(&mut self, err: &VmError) -> String {
+        let (instruction, _) =
+            disassembler::disassemble_instruction(self.vm.get_program(), err.pc());
+        format!("{}\n    at {:04}  {}\n", err, err.pc(), instruction)
+    }
+
+    /// Formats `len` bytes of the heap starting at `start` in rows of 8.
+    fn format_heap(&self, start: usize, len: usize) -> String {
+        let heap = self.vm.get_heap();
+        let bytes = match start.checked_add(len).and_then(|end| heap.get(start..end)) {
+            Some(bytes) => bytes,
+            None => return format!("out of bounds, the heap is {} bytes\n", heap.len()),
+        };
+        let mut text = String::new();
+        for (i, row) in bytes.chunks(8).enumerate() {
+            let row: Vec<String> = row.iter().map(|b| format!("{:02X}", b)).collect();
+            text.push_str(&format!("    {:04}  {}\n", start + i * 8, row.join(" ")));
         }
+        text
     }
 
     /// Accepts a hexadecimal string without a leading '0x' and returns a Vec of u8.
@@ -111,7 +339,7 @@ impl REPL {
         let mut results: Vec<u8> = vec![];
 
         for hex_str in splitted {
-            let byte = u8::from_str_radix(&hex_str, 16);
+            let byte = u8::from_str_radix(hex_str, 16);
             match byte {
                 Ok(result) => {
                     results.push(result);
@@ -147,7 +375,97 @@ mod tests {
                 panic!();
             }
         }
-        repl.vm.run();
+        repl.vm.run().unwrap();
         assert_eq!(repl.vm.get_registers()[0], 1000);
     }
+
+    #[test]
+    fn test_debugger() {
+        let mut repl = REPL::new();
+        let source = String::from("LOAD $1 #3\nloop: INC $0\nLT $0 $1\nJEQ $2\nHLT");
+        let mut assembler = Assembler::new("<test>", &source);
+        let program = assembler.compile().unwrap();
+        repl.symbols = program.symbols.clone();
+        repl.vm.load_program(&program);
+        repl.vm.set_register(2, 4);
+
+        let mut run = |line: &str| repl.debug_command(line).unwrap();
+        assert_eq!(run(".pc"), "    0000  LOAD $1 #3\n");
+        assert_eq!(run(".break loop"), "breakpoint at 0004\n");
+        assert_eq!(run(".continue"), "breakpoint\n    0004  INC $0\n");
+        assert_eq!(run(".step"), "    0006  LT $0 $1\n");
+        assert_eq!(run(".step"), "    0009  JEQ $2\n");
+        assert_eq!(run(".flag"), "true\n");
+        assert_eq!(run(".set $0 9"), "$0 = 9\n");
+        assert_eq!(run(".continue"), "breakpoint\n    0004  INC $0\n");
+        assert_eq!(run(".continue"), "halted\n");
+        assert_eq!(run(".heap 0 1"), "out of bounds, the heap is 0 bytes\n");
+        assert_eq!(run(".break"), "breakpoint at 0004\n");
+        assert_eq!(run(".break nowhere"), "unknown offset or label 'nowhere'\n");
+        assert!(repl.debug_command(".registers").is_none());
+    }
+
+    #[test]
+    fn test_profile_command() {
+        let mut repl = REPL::new();
+        repl.vm.load_program(
+            &Assembler::new("<test>", &String::from("INC $0\nHLT"))
+                .compile()
+                .unwrap(),
+        );
+
+        assert_eq!(
+            repl.debug_command(".profile").unwrap(),
+            "profiling is off, start it with .profile on\n"
+        );
+        repl.debug_command(".profile on");
+        repl.debug_command(".continue");
+        let report = repl.debug_command(".profile off").unwrap();
+        assert!(
+            report.starts_with("instructions executed: 2\n"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn test_labels_across_entries() {
+        let mut repl = REPL::new();
+        assert_eq!(repl.enter_source("LOAD $1 #3"), "");
+        repl.enter_source("loop: INC $0\nLT $0 $1\nLOAD $2 @loop\nJEQ $2");
+        assert_eq!(repl.vm.get_registers()[0], 3);
+
+        // A label declared in an earlier entry.
+        repl.enter_source("LOAD $3 @loop");
+        assert_eq!(repl.vm.get_registers()[3], 4);
+
+        // Entries that do not assemble are discarded.
+        let source = repl.source.clone();
+        assert!(repl
+            .enter_source("LOAD $4 @nowhere")
+            .starts_with("error[E0008]: undefined label 'nowhere'\n --> <input>:7:9\n"));
+        assert_eq!(repl.source, source);
+        repl.enter_source("INC $4");
+        assert_eq!(repl.vm.get_registers()[4], 1);
+    }
+
+    #[test]
+    fn test_load_file() {
+        let path = std::env::temp_dir().join(format!("fvm_repl_{}.fasm", std::process::id()));
+        std::fs::write(&path, "start: INC $0\nHLT").unwrap();
+
+        let mut repl = REPL::new();
+        repl.enter_source("LOAD $0 #7");
+        assert_eq!(
+            repl.load_file(&path),
+            format!("loaded {} (3 bytes)\n", path.display())
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(repl.vm.get_pc(), 0);
+        assert_eq!(repl.symbols.symbol_value("start"), Some(0));
+
+        assert_eq!(repl.debug_command(".continue").unwrap(), "halted\n");
+        assert_eq!(repl.vm.get_registers()[0], 8);
+        assert!(repl.load_file(&path).starts_with("cannot read"));
+    }
 }
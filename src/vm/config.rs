@@ -0,0 +1,31 @@
+use crate::instruction::Opcode;
+use std::collections::HashMap;
+
+/// Limits applied to a `VM`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmConfig {
+    /// Maximum number of values the stack may hold, including return addresses pushed by `CALL`.
+    pub max_stack_depth: usize,
+    /// Maximum size of the heap in bytes.
+    pub max_heap_size: usize,
+    /// Fuel consumed by each opcode when running with `VM::run_with_budget`. Opcodes that are
+    /// not listed cost 1.
+    pub opcode_costs: HashMap<Opcode, u64>,
+}
+
+impl VmConfig {
+    /// Returns the fuel consumed by executing `opcode`.
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.opcode_costs.get(&opcode).copied().unwrap_or(1)
+    }
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            max_stack_depth: 1024,
+            max_heap_size: 16 * 1024 * 1024,
+            opcode_costs: HashMap::new(),
+        }
+    }
+}
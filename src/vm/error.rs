@@ -0,0 +1,199 @@
+use crate::instruction::Opcode;
+use std::fmt;
+
+/// Errors raised while executing bytecode. Every variant records the `pc` of the instruction
+/// that faulted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmError {
+    /// `DIV` with a zero divisor.
+    DivideByZero { pc: usize, opcode: Opcode },
+    /// An instruction referred to a register that does not exist.
+    InvalidRegister {
+        pc: usize,
+        opcode: Opcode,
+        register: u8,
+    },
+    /// The program ended in the middle of an instruction's operands.
+    TruncatedInstruction { pc: usize, opcode: Opcode },
+    /// A jump would have moved the counter outside of the program.
+    PcOutOfBounds {
+        pc: usize,
+        opcode: Opcode,
+        target: i64,
+    },
+    /// The byte at `pc` is not a known opcode.
+    IllegalOpcode { pc: usize, byte: u8 },
+    /// The heap would have shrunk below zero bytes or grown past `VmConfig::max_heap_size`.
+    HeapOverflow {
+        pc: usize,
+        opcode: Opcode,
+        requested: i64,
+    },
+    /// `ALOC` or `FREE` was given a negative number of bytes.
+    InvalidAllocation {
+        pc: usize,
+        opcode: Opcode,
+        size: i32,
+    },
+    /// A heap access fell outside of the heap.
+    HeapOutOfBounds {
+        pc: usize,
+        opcode: Opcode,
+        address: i64,
+    },
+    /// A push would have grown the stack past `VmConfig::max_stack_depth`.
+    StackOverflow {
+        pc: usize,
+        opcode: Opcode,
+        depth: usize,
+    },
+    /// A value was popped from an empty stack.
+    StackUnderflow { pc: usize, opcode: Opcode },
+    /// A string in the read-only data started outside of it or was not terminated.
+    DataOutOfBounds {
+        pc: usize,
+        opcode: Opcode,
+        offset: usize,
+    },
+    /// `CALLN` referred to a native function that has not been registered.
+    UnknownNative { pc: usize, opcode: Opcode, id: u16 },
+    /// A native function returned an error.
+    Native {
+        pc: usize,
+        opcode: Opcode,
+        name: String,
+        message: String,
+    },
+    /// Writing to the output failed.
+    Output {
+        pc: usize,
+        opcode: Opcode,
+        message: String,
+    },
+}
+
+impl VmError {
+    /// Returns the program counter of the faulting instruction.
+    pub fn pc(&self) -> usize {
+        match self {
+            VmError::DivideByZero { pc, .. }
+            | VmError::InvalidRegister { pc, .. }
+            | VmError::TruncatedInstruction { pc, .. }
+            | VmError::PcOutOfBounds { pc, .. }
+            | VmError::IllegalOpcode { pc, .. }
+            | VmError::HeapOverflow { pc, .. }
+            | VmError::InvalidAllocation { pc, .. }
+            | VmError::HeapOutOfBounds { pc, .. }
+            | VmError::StackOverflow { pc, .. }
+            | VmError::StackUnderflow { pc, .. }
+            | VmError::DataOutOfBounds { pc, .. }
+            | VmError::UnknownNative { pc, .. }
+            | VmError::Native { pc, .. }
+            | VmError::Output { pc, .. } => *pc,
+        }
+    }
+
+    /// Returns the opcode of the faulting instruction.
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            VmError::DivideByZero { opcode, .. }
+            | VmError::InvalidRegister { opcode, .. }
+            | VmError::TruncatedInstruction { opcode, .. }
+            | VmError::PcOutOfBounds { opcode, .. }
+            | VmError::HeapOverflow { opcode, .. }
+            | VmError::InvalidAllocation { opcode, .. }
+            | VmError::HeapOutOfBounds { opcode, .. }
+            | VmError::StackOverflow { opcode, .. }
+            | VmError::StackUnderflow { opcode, .. }
+            | VmError::DataOutOfBounds { opcode, .. }
+            | VmError::UnknownNative { opcode, .. }
+            | VmError::Native { opcode, .. }
+            | VmError::Output { opcode, .. } => *opcode,
+            VmError::IllegalOpcode { .. } => Opcode::IGL,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::DivideByZero { pc, opcode } => {
+                write!(f, "{:?} at {}: division by zero", opcode, pc)
+            }
+            VmError::InvalidRegister {
+                pc,
+                opcode,
+                register,
+            } => write!(f, "{:?} at {}: invalid register ${}", opcode, pc, register),
+            VmError::TruncatedInstruction { pc, opcode } => {
+                write!(f, "{:?} at {}: truncated instruction", opcode, pc)
+            }
+            VmError::PcOutOfBounds { pc, opcode, target } => {
+                write!(
+                    f,
+                    "{:?} at {}: jump target {} is out of bounds",
+                    opcode, pc, target
+                )
+            }
+            VmError::IllegalOpcode { pc, byte } => {
+                write!(f, "illegal opcode {} at {}", byte, pc)
+            }
+            VmError::HeapOverflow {
+                pc,
+                opcode,
+                requested,
+            } => write!(
+                f,
+                "{:?} at {}: cannot resize the heap to {} bytes",
+                opcode, pc, requested
+            ),
+            VmError::InvalidAllocation { pc, opcode, size } => write!(
+                f,
+                "{:?} at {}: invalid allocation size {}",
+                opcode, pc, size
+            ),
+            VmError::HeapOutOfBounds {
+                pc,
+                opcode,
+                address,
+            } => write!(
+                f,
+                "{:?} at {}: heap address {} is out of bounds",
+                opcode, pc, address
+            ),
+            VmError::StackOverflow { pc, opcode, depth } => write!(
+                f,
+                "{:?} at {}: stack overflow (maximum depth is {})",
+                opcode, pc, depth
+            ),
+            VmError::StackUnderflow { pc, opcode } => {
+                write!(f, "{:?} at {}: stack underflow", opcode, pc)
+            }
+            VmError::DataOutOfBounds { pc, opcode, offset } => write!(
+                f,
+                "{:?} at {}: no terminated string at data offset {}",
+                opcode, pc, offset
+            ),
+            VmError::UnknownNative { pc, opcode, id } => {
+                write!(f, "{:?} at {}: unknown native function #{}", opcode, pc, id)
+            }
+            VmError::Native {
+                pc,
+                opcode,
+                name,
+                message,
+            } => write!(f, "{:?} at {}: {}: {}", opcode, pc, name, message),
+            VmError::Output {
+                pc,
+                opcode,
+                message,
+            } => write!(
+                f,
+                "{:?} at {}: cannot write output: {}",
+                opcode, pc, message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
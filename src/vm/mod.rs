@@ -0,0 +1,1491 @@
+pub mod config;
+pub mod error;
+pub mod native;
+pub mod profile;
+pub mod snapshot;
+pub mod trace;
+
+use self::config::VmConfig;
+use self::error::VmError;
+use self::native::{Native, NativeFunction};
+use self::profile::Profile;
+use self::trace::{TraceEntry, Tracer};
+use crate::instruction::{DecodeError, Instruction, Opcode};
+use crate::program::{Program, ProgramError};
+use std::io::{self, Write};
+use std::time::Instant;
+
+/// Describes why execution stopped without an error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// An instruction was executed and more instructions can follow.
+    Running,
+    /// A `HLT` instruction was executed.
+    Halted,
+    /// The program counter reached the end of the program.
+    Finished,
+    /// `run_with_budget` used up its fuel before the next instruction. Running again continues
+    /// from that instruction.
+    OutOfFuel,
+}
+
+pub struct VM {
+    /// Array that simulates having hardware registers
+    registers: [i32; 32],
+    /// Array that simulates having hardware floating-point registers
+    float_registers: [f64; 32],
+    /// Heap for the VM
+    heap: Vec<u8>,
+    /// Program counter that tracks which byte is being executed
+    pc: usize,
+    /// Program counter of the instruction currently being executed, used to report faults
+    instruction_pc: usize,
+    /// The bytecodes of the program being executed
+    program: Vec<u8>,
+    /// The read-only data section of the program being executed
+    ro_data: Vec<u8>,
+    /// Contains the remainder of the last division
+    remainder: u32,
+    /// Contains the result of the last comparison operation, which `JEQ` and `JNEQ` jump on
+    equal_flag: bool,
+    /// Values pushed by `PUSH` and return addresses pushed by `CALL`; its length is the stack pointer
+    stack: Vec<i32>,
+    /// Limits applied while executing
+    config: VmConfig,
+    /// Where `PRTI`, `PRTS` and `PRTH` write to
+    output: Box<dyn Write>,
+    /// Functions callable with `CALLN`, indexed by id
+    natives: Vec<Native>,
+    /// Receives every executed instruction, if set
+    tracer: Option<Box<dyn Tracer>>,
+    /// Execution counts, if profiling
+    profile: Option<Profile>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    pub fn new() -> Self {
+        Self::with_config(VmConfig::default())
+    }
+
+    pub fn with_config(config: VmConfig) -> Self {
+        VM {
+            registers: [0; 32],
+            float_registers: [0.0; 32],
+            heap: vec![],
+            pc: 0,
+            instruction_pc: 0,
+            program: vec![],
+            ro_data: vec![],
+            remainder: 0,
+            equal_flag: false,
+            stack: vec![],
+            config,
+            output: Box::new(io::stdout()),
+            natives: vec![],
+            tracer: None,
+            profile: None,
+        }
+    }
+
+    /// Starts counting executed instructions, discarding any previous profile. If `timed`, the
+    /// wall-clock time of each instruction is measured as well, which slows down execution.
+    pub fn enable_profiling(&mut self, timed: bool) {
+        self.profile = Some(Profile::new(timed));
+    }
+
+    /// Stops profiling and returns the profile collected.
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    pub fn get_profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Sets the tracer that records every instruction executed from now on. Tracing slows down
+    /// execution, so it is off by default.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Removes the tracer and returns it.
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracer.take()
+    }
+
+    /// Registers a native function taking `arity` arguments and returns the id that `CALLN`
+    /// calls it with. Registering a name again replaces the function but keeps its id.
+    pub fn register_native(&mut self, name: &str, arity: u8, function: NativeFunction) -> u16 {
+        let native = Native {
+            name: name.to_string(),
+            arity: arity.min(self.registers.len() as u8),
+            function,
+        };
+        match self.native_id(name) {
+            Some(id) => {
+                self.natives[id as usize] = native;
+                id
+            }
+            None => {
+                self.natives.push(native);
+                (self.natives.len() - 1) as u16
+            }
+        }
+    }
+
+    /// Returns the id of the native function registered under `name`.
+    pub fn native_id(&self, name: &str) -> Option<u16> {
+        self.natives
+            .iter()
+            .position(|native| native.name == name)
+            .map(|id| id as u16)
+    }
+
+    pub fn get_natives(&self) -> &Vec<Native> {
+        &self.natives
+    }
+
+    /// Replaces the output that printing instructions write to, which is stdout by default.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    pub fn get_program(&self) -> &Vec<u8> {
+        &self.program
+    }
+
+    pub fn get_ro_data(&self) -> &Vec<u8> {
+        &self.ro_data
+    }
+
+    pub fn get_registers(&mut self) -> [i32; 32] {
+        self.registers
+    }
+
+    /// Sets register `register` to `value`. Panics if there is no such register.
+    pub fn set_register(&mut self, register: usize, value: i32) {
+        self.registers[register] = value;
+    }
+
+    /// Returns the program counter, which is the offset of the next instruction to execute.
+    pub fn get_pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Returns the result of the last comparison.
+    pub fn get_equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    pub fn get_stack(&self) -> &Vec<i32> {
+        &self.stack
+    }
+
+    /// Returns the stack pointer, which is the number of values on the stack.
+    pub fn get_stack_pointer(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn get_heap(&self) -> &Vec<u8> {
+        &self.heap
+    }
+
+    pub fn get_float_registers(&self) -> [f64; 32] {
+        self.float_registers
+    }
+
+    pub fn add_byte(&mut self, b: u8) {
+        self.program.push(b);
+    }
+
+    /// Replaces the program being executed and moves the counter to its entry point.
+    pub fn load_program(&mut self, program: &Program) {
+        self.program = program.code.clone();
+        self.ro_data = program.ro_data.clone();
+        self.pc = program.entry_point as usize;
+    }
+
+    /// Replaces the code and read-only data without moving the counter, for a program that grew
+    /// since it was loaded.
+    pub fn update_program(&mut self, program: &Program) {
+        self.program = program.code.clone();
+        self.ro_data = program.ro_data.clone();
+    }
+
+    /// Loads a program from the bytes of a program file, rejecting invalid or incompatible files.
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), ProgramError> {
+        let program = Program::from_bytes(bytes)?;
+        self.load_program(&program);
+        Ok(())
+    }
+
+    /// Loops through the instructions as long as instructions can be executed.
+    pub fn run(&mut self) -> Result<ExitStatus, VmError> {
+        loop {
+            match self.step()? {
+                ExitStatus::Running => {}
+                status => return Ok(status),
+            }
+        }
+    }
+
+    /// Like `run`, but stops with `ExitStatus::OutOfFuel` before executing an instruction that
+    /// costs more than the fuel left out of `budget`. Costs are set by `VmConfig::opcode_costs`.
+    pub fn run_with_budget(&mut self, budget: u64) -> Result<ExitStatus, VmError> {
+        let mut fuel = budget;
+        loop {
+            if let Some(&byte) = self.program.get(self.pc) {
+                let cost = self.config.cost(Opcode::from(byte));
+                if cost > fuel {
+                    return Ok(ExitStatus::OutOfFuel);
+                }
+                fuel -= cost;
+            }
+            match self.step()? {
+                ExitStatus::Running => {}
+                status => return Ok(status),
+            }
+        }
+    }
+
+    /// Executes only one single instruction.
+    pub fn run_once(&mut self) -> Result<ExitStatus, VmError> {
+        self.step()
+    }
+
+    /// Executes single instruction, counting it if profiling.
+    fn step(&mut self) -> Result<ExitStatus, VmError> {
+        let profile = match self.profile.as_ref() {
+            Some(profile) if self.pc < self.program.len() => profile,
+            _ => return self.execute_traced(),
+        };
+        let pc = self.pc;
+        // Read before executing, as a native function may replace the program.
+        let opcode = Opcode::from(self.program[pc]);
+        let start = profile.is_timed().then(Instant::now);
+        let result = self.execute_traced();
+        if let Some(profile) = self.profile.as_mut() {
+            profile.record(pc, opcode, start.map(|start| start.elapsed()));
+        }
+        result
+    }
+
+    /// Executes single instruction, recording it if a tracer is set.
+    fn execute_traced(&mut self) -> Result<ExitStatus, VmError> {
+        // Running off the end of the program executes no instruction.
+        if self.tracer.is_none() || self.pc >= self.program.len() {
+            return self.execute_instruction();
+        }
+
+        let pc = self.pc;
+        let registers = self.registers;
+        let float_registers = self.float_registers;
+        let equal_flag = self.equal_flag;
+        let result = self.execute_instruction();
+
+        let instruction = match self.program.get(pc..).map(Instruction::decode) {
+            Some(Ok(instruction)) => instruction,
+            _ => return result,
+        };
+        let entry = TraceEntry {
+            pc,
+            instruction,
+            register_writes: (0..registers.len())
+                .filter(|&i| registers[i] != self.registers[i])
+                .map(|i| (i, self.registers[i]))
+                .collect(),
+            float_register_writes: (0..float_registers.len())
+                .filter(|&i| float_registers[i].to_bits() != self.float_registers[i].to_bits())
+                .map(|i| (i, self.float_registers[i]))
+                .collect(),
+            equal_flag: (equal_flag != self.equal_flag).then_some(self.equal_flag),
+        };
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record(&entry);
+        }
+        result
+    }
+
+    /// Executes single instruction and returns the resulting status.
+    fn execute_instruction(&mut self) -> Result<ExitStatus, VmError> {
+        if self.pc >= self.program.len() {
+            return Ok(ExitStatus::Finished);
+        }
+        self.instruction_pc = self.pc;
+        let instruction = self.decode_instruction()?;
+        self.pc += instruction.encoded_len();
+        // Operand layouts are defined in `instruction::INSTRUCTIONS`.
+        match instruction.opcode {
+            // LOAD $0 #number
+            // Load [number] to register [0]
+            Opcode::LOAD => {
+                let register = self.register(instruction.operand(0))?;
+                self.registers[register] = i32::from(instruction.operand(1) as u16);
+            }
+            // ADD $0 $1 $2
+            // Add [0] and [1], and then store the result to register [2].
+            Opcode::ADD => {
+                let (register1, register2, destination) = self.three_registers(&instruction)?;
+                self.registers[destination] = register1.wrapping_add(register2);
+            }
+            // SUB $0 $1 $2
+            // Subtract [1] from [0], and then store the result to register [2].
+            Opcode::SUB => {
+                let (register1, register2, destination) = self.three_registers(&instruction)?;
+                self.registers[destination] = register1.wrapping_sub(register2);
+            }
+            // MUL $0 $1 $2
+            // Multiply [0] by [1], and then store the result to register [2].
+            Opcode::MUL => {
+                let (register1, register2, destination) = self.three_registers(&instruction)?;
+                self.registers[destination] = register1.wrapping_mul(register2);
+            }
+            // DIV $0 $1 $2
+            // Divide [0] by [1], and then store the result to register [2], and the remainder is
+            // stored in `remainder`.
+            Opcode::DIV => {
+                let (register1, register2, destination) = self.three_registers(&instruction)?;
+                if register2 == 0 {
+                    return Err(VmError::DivideByZero {
+                        pc: self.instruction_pc,
+                        opcode: Opcode::DIV,
+                    });
+                }
+                self.registers[destination] = register1.wrapping_div(register2);
+                self.remainder = register1.wrapping_rem(register2) as u32;
+            }
+            // JMP $0
+            // Set the counter to the value of register [0].
+            Opcode::JMP => {
+                let target = self.registers[self.register(instruction.operand(0))?];
+                self.jump_to(i64::from(target))?;
+            }
+            // JMPF $0
+            // Add the value of register [0] to the counter. (relative forward jump)
+            Opcode::JMPF => {
+                let value = self.registers[self.register(instruction.operand(0))?];
+                self.jump_to(self.pc as i64 + i64::from(value))?;
+            }
+            // JMPB $0
+            // Subtract the value of register [0] from the counter. (relative backward jump)
+            Opcode::JMPB => {
+                let value = self.registers[self.register(instruction.operand(0))?];
+                self.jump_to(self.pc as i64 - i64::from(value))?;
+            }
+            // EQ $0 $1, NEQ $0 $1, GT $0 $1, GTE $0 $1, LT $0 $1, LTE $0 $1
+            // Compares the values of register [0] and register [1] as signed integers, and stores
+            // the result to `equal_flag`. For example, `LT $0 $1` checks if [0] < [1].
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::GTE | Opcode::LT | Opcode::LTE => {
+                let register1 = self.registers[self.register(instruction.operand(0))?];
+                let register2 = self.registers[self.register(instruction.operand(1))?];
+                self.equal_flag = match instruction.opcode {
+                    Opcode::EQ => register1 == register2,
+                    Opcode::NEQ => register1 != register2,
+                    Opcode::GT => register1 > register2,
+                    Opcode::GTE => register1 >= register2,
+                    Opcode::LT => register1 < register2,
+                    _ => register1 <= register2,
+                };
+            }
+            // JEQ $0
+            // If `equal_flag` is true, set the counter to the value of register [0].
+            Opcode::JEQ => {
+                let target = self.registers[self.register(instruction.operand(0))?];
+                if self.equal_flag {
+                    self.jump_to(i64::from(target))?;
+                }
+            }
+            // JNEQ $0
+            // If `equal_flag` is not true, set the counter to the value of register [0].
+            Opcode::JNEQ => {
+                let target = self.registers[self.register(instruction.operand(0))?];
+                if !self.equal_flag {
+                    self.jump_to(i64::from(target))?;
+                }
+            }
+            // ALOC $0
+            // Extends the size of the heap vector by the amount in the register [0].
+            Opcode::ALOC => {
+                let bytes = self.allocation_size(&instruction)?;
+                self.resize_heap(self.heap.len() as i64 + i64::from(bytes))?;
+            }
+            // FREE $0
+            // Shrinks the size of the heap vector by the amount in the register [0].
+            Opcode::FREE => {
+                let bytes = self.allocation_size(&instruction)?;
+                self.resize_heap(self.heap.len() as i64 - i64::from(bytes))?;
+            }
+            // LOADB $0 $1
+            // Loads the byte at the heap address in register [1] to register [0].
+            Opcode::LOADB => {
+                let destination = self.register(instruction.operand(0))?;
+                let address = self.registers[self.register(instruction.operand(1))?];
+                let bytes = self.heap_slice(address, 1)?;
+                self.registers[destination] = i32::from(bytes[0]);
+            }
+            // STOREB $0 $1
+            // Stores the lowest byte of register [0] to the heap address in register [1].
+            Opcode::STOREB => {
+                let value = self.registers[self.register(instruction.operand(0))?];
+                let address = self.registers[self.register(instruction.operand(1))?];
+                self.heap_slice(address, 1)?[0] = value as u8;
+            }
+            // LOADW $0 $1
+            // Loads the big-endian word at the heap address in register [1] to register [0].
+            Opcode::LOADW => {
+                let destination = self.register(instruction.operand(0))?;
+                let address = self.registers[self.register(instruction.operand(1))?];
+                let bytes = self.heap_slice(address, 4)?;
+                self.registers[destination] =
+                    i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+            // STOREW $0 $1
+            // Stores register [0] as a big-endian word to the heap address in register [1].
+            Opcode::STOREW => {
+                let value = self.registers[self.register(instruction.operand(0))?];
+                let address = self.registers[self.register(instruction.operand(1))?];
+                self.heap_slice(address, 4)?
+                    .copy_from_slice(&value.to_be_bytes());
+            }
+            // INC $0
+            // Increments the value stored in register [0] by 1.
+            Opcode::INC => {
+                let register = self.register(instruction.operand(0))?;
+                self.registers[register] = self.registers[register].wrapping_add(1);
+            }
+            // DEC $0
+            // Decrements the value stored in register [0] by 1.
+            Opcode::DEC => {
+                let register = self.register(instruction.operand(0))?;
+                self.registers[register] = self.registers[register].wrapping_sub(1);
+            }
+            // CALL @address
+            // Pushes the address of the next instruction to the stack and sets the counter to
+            // [address].
+            Opcode::CALL => {
+                self.push(self.pc as i32)?;
+                self.jump_to(instruction.operand(0) as i64)?;
+            }
+            // RET
+            // Pops a return address pushed by `CALL` and sets the counter to it.
+            Opcode::RET => {
+                let target = self.pop()?;
+                self.jump_to(i64::from(target))?;
+            }
+            // PUSH $0
+            // Pushes the value of register [0] to the stack.
+            Opcode::PUSH => {
+                let value = self.registers[self.register(instruction.operand(0))?];
+                self.push(value)?;
+            }
+            // POP $0
+            // Pops a value from the stack and stores it to register [0].
+            Opcode::POP => {
+                let register = self.register(instruction.operand(0))?;
+                self.registers[register] = self.pop()?;
+            }
+            // LOADF $f0 #float
+            // Load [float] to float register [0].
+            Opcode::LOADF => {
+                let register = self.float_register(instruction.operand(0))?;
+                self.float_registers[register] = instruction.float_operand(1);
+            }
+            // ADDF $f0 $f1 $f2
+            // Add float registers [0] and [1], and then store the result to float register [2].
+            Opcode::ADDF => {
+                let (register1, register2, destination) =
+                    self.three_float_registers(&instruction)?;
+                self.float_registers[destination] = register1 + register2;
+            }
+            // SUBF $f0 $f1 $f2
+            // Subtract [1] from [0], and then store the result to float register [2].
+            Opcode::SUBF => {
+                let (register1, register2, destination) =
+                    self.three_float_registers(&instruction)?;
+                self.float_registers[destination] = register1 - register2;
+            }
+            // MULF $f0 $f1 $f2
+            // Multiply [0] by [1], and then store the result to float register [2].
+            Opcode::MULF => {
+                let (register1, register2, destination) =
+                    self.three_float_registers(&instruction)?;
+                self.float_registers[destination] = register1 * register2;
+            }
+            // DIVF $f0 $f1 $f2
+            // Divide [0] by [1], and then store the result to float register [2]. Dividing by
+            // zero follows IEEE 754 and produces an infinity or NaN.
+            Opcode::DIVF => {
+                let (register1, register2, destination) =
+                    self.three_float_registers(&instruction)?;
+                self.float_registers[destination] = register1 / register2;
+            }
+            // EQF $f0 $f1, NEQF $f0 $f1, GTF $f0 $f1, GTEF $f0 $f1, LTF $f0 $f1, LTEF $f0 $f1
+            // Compares float registers [0] and [1], and stores the result to `equal_flag`. Every
+            // comparison except NEQF is false if either value is NaN.
+            Opcode::EQF
+            | Opcode::NEQF
+            | Opcode::GTF
+            | Opcode::GTEF
+            | Opcode::LTF
+            | Opcode::LTEF => {
+                let register1 =
+                    self.float_registers[self.float_register(instruction.operand(0))?];
+                let register2 =
+                    self.float_registers[self.float_register(instruction.operand(1))?];
+                self.equal_flag = match instruction.opcode {
+                    Opcode::EQF => register1 == register2,
+                    Opcode::NEQF => register1 != register2,
+                    Opcode::GTF => register1 > register2,
+                    Opcode::GTEF => register1 >= register2,
+                    Opcode::LTF => register1 < register2,
+                    _ => register1 <= register2,
+                };
+            }
+            // ITOF $f0 $1
+            // Converts the value of register [1] to a float, and stores it to float register [0].
+            Opcode::ITOF => {
+                let destination = self.float_register(instruction.operand(0))?;
+                let value = self.registers[self.register(instruction.operand(1))?];
+                self.float_registers[destination] = f64::from(value);
+            }
+            // FTOI $0 $f1
+            // Truncates the value of float register [1] to an integer, and stores it to register
+            // [0]. Values out of range saturate, and NaN becomes 0.
+            Opcode::FTOI => {
+                let destination = self.register(instruction.operand(0))?;
+                let value = self.float_registers[self.float_register(instruction.operand(1))?];
+                self.registers[destination] = value as i32;
+            }
+            // PRTI $0
+            // Prints the value of register [0] as a decimal number.
+            Opcode::PRTI => {
+                let value = self.registers[self.register(instruction.operand(0))?];
+                self.write_output(value.to_string().as_bytes())?;
+            }
+            // PRTS @offset
+            // Prints the NUL-terminated string at [offset] in the read-only data.
+            Opcode::PRTS => {
+                let offset = instruction.operand(0) as usize;
+                let string = self
+                    .ro_data
+                    .get(offset..)
+                    .and_then(|data| data.iter().position(|&b| b == 0).map(|end| &data[..end]))
+                    .ok_or(VmError::DataOutOfBounds {
+                        pc: self.instruction_pc,
+                        opcode: Opcode::PRTS,
+                        offset,
+                    })?
+                    .to_vec();
+                self.write_output(&string)?;
+            }
+            // PRTH $0 $1
+            // Prints the number of bytes in register [1] starting at the heap address in
+            // register [0].
+            Opcode::PRTH => {
+                let address = self.registers[self.register(instruction.operand(0))?];
+                let len = self.registers[self.register(instruction.operand(1))?];
+                if len < 0 {
+                    return Err(VmError::HeapOutOfBounds {
+                        pc: self.instruction_pc,
+                        opcode: Opcode::PRTH,
+                        address: i64::from(address),
+                    });
+                }
+                let bytes = self.heap_slice(address, len as usize)?.to_vec();
+                self.write_output(&bytes)?;
+            }
+            // CALLN #id
+            // Calls the native function [id] with the values of registers 0 and up as arguments,
+            // and stores its result to register 0.
+            Opcode::CALLN => {
+                let id = instruction.operand(0) as u16;
+                let native =
+                    self.natives
+                        .get(id as usize)
+                        .cloned()
+                        .ok_or(VmError::UnknownNative {
+                            pc: self.instruction_pc,
+                            opcode: Opcode::CALLN,
+                            id,
+                        })?;
+                let args = self.registers[..native.arity as usize].to_vec();
+                self.registers[0] =
+                    (native.function)(self, &args).map_err(|message| VmError::Native {
+                        pc: self.instruction_pc,
+                        opcode: Opcode::CALLN,
+                        name: native.name,
+                        message,
+                    })?;
+            }
+            Opcode::HLT => return Ok(ExitStatus::Halted),
+            Opcode::IGL => unreachable!("illegal opcodes are rejected while decoding"),
+        }
+        Ok(ExitStatus::Running)
+    }
+
+    /// Decodes the instruction at the counter without advancing it.
+    fn decode_instruction(&self) -> Result<Instruction, VmError> {
+        Instruction::decode(&self.program[self.pc..]).map_err(|err| match err {
+            DecodeError::Empty => {
+                unreachable!("the counter is checked against the program length first")
+            }
+            DecodeError::IllegalOpcode(byte) => VmError::IllegalOpcode { pc: self.pc, byte },
+            DecodeError::Truncated(opcode) => VmError::TruncatedInstruction {
+                pc: self.pc,
+                opcode,
+            },
+        })
+    }
+
+    /// Returns the opcode of the instruction currently being executed.
+    fn current_opcode(&self) -> Opcode {
+        Opcode::from(self.program[self.instruction_pc])
+    }
+
+    /// Checks that a register operand is an index into `registers`.
+    fn register(&self, operand: u64) -> Result<usize, VmError> {
+        let register = operand as usize;
+        if register < self.registers.len() {
+            Ok(register)
+        } else {
+            Err(VmError::InvalidRegister {
+                pc: self.instruction_pc,
+                opcode: self.current_opcode(),
+                register: operand as u8,
+            })
+        }
+    }
+
+    /// Checks that a float register operand is an index into `float_registers`.
+    fn float_register(&self, operand: u64) -> Result<usize, VmError> {
+        let register = operand as usize;
+        if register < self.float_registers.len() {
+            Ok(register)
+        } else {
+            Err(VmError::InvalidRegister {
+                pc: self.instruction_pc,
+                opcode: self.current_opcode(),
+                register: operand as u8,
+            })
+        }
+    }
+
+    /// Returns the values of the first two register operands and the index of the third.
+    fn three_registers(&self, instruction: &Instruction) -> Result<(i32, i32, usize), VmError> {
+        Ok((
+            self.registers[self.register(instruction.operand(0))?],
+            self.registers[self.register(instruction.operand(1))?],
+            self.register(instruction.operand(2))?,
+        ))
+    }
+
+    /// Returns the values of the first two float register operands and the index of the third.
+    fn three_float_registers(
+        &self,
+        instruction: &Instruction,
+    ) -> Result<(f64, f64, usize), VmError> {
+        Ok((
+            self.float_registers[self.float_register(instruction.operand(0))?],
+            self.float_registers[self.float_register(instruction.operand(1))?],
+            self.float_register(instruction.operand(2))?,
+        ))
+    }
+
+    /// Returns the number of bytes in the register operand of `ALOC` or `FREE`, which must not be
+    /// negative.
+    fn allocation_size(&self, instruction: &Instruction) -> Result<i32, VmError> {
+        let size = self.registers[self.register(instruction.operand(0))?];
+        if size < 0 {
+            return Err(VmError::InvalidAllocation {
+                pc: self.instruction_pc,
+                opcode: instruction.opcode,
+                size,
+            });
+        }
+        Ok(size)
+    }
+
+    /// Resizes the heap to `new_end` bytes, zeroing any new bytes. The size must stay within
+    /// `VmConfig::max_heap_size`.
+    fn resize_heap(&mut self, new_end: i64) -> Result<(), VmError> {
+        if new_end < 0 || new_end as u64 > self.config.max_heap_size as u64 {
+            return Err(VmError::HeapOverflow {
+                pc: self.instruction_pc,
+                opcode: self.current_opcode(),
+                requested: new_end,
+            });
+        }
+        self.heap.resize(new_end as usize, 0);
+        Ok(())
+    }
+
+    /// Returns the `len` bytes of the heap starting at `address`.
+    fn heap_slice(&mut self, address: i32, len: usize) -> Result<&mut [u8], VmError> {
+        let start = address as usize;
+        if address < 0 || start + len > self.heap.len() {
+            return Err(VmError::HeapOutOfBounds {
+                pc: self.instruction_pc,
+                opcode: self.current_opcode(),
+                address: i64::from(address),
+            });
+        }
+        Ok(&mut self.heap[start..start + len])
+    }
+
+    /// Writes `bytes` to the output and flushes it, so that output is visible even if the
+    /// process exits right after.
+    fn write_output(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        self.output
+            .write_all(bytes)
+            .and_then(|_| self.output.flush())
+            .map_err(|err| VmError::Output {
+                pc: self.instruction_pc,
+                opcode: self.current_opcode(),
+                message: err.to_string(),
+            })
+    }
+
+    fn push(&mut self, value: i32) -> Result<(), VmError> {
+        if self.stack.len() >= self.config.max_stack_depth {
+            return Err(VmError::StackOverflow {
+                pc: self.instruction_pc,
+                opcode: self.current_opcode(),
+                depth: self.config.max_stack_depth,
+            });
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i32, VmError> {
+        self.stack.pop().ok_or_else(|| VmError::StackUnderflow {
+            pc: self.instruction_pc,
+            opcode: self.current_opcode(),
+        })
+    }
+
+    /// Sets the counter to `target`, which may point at most one past the end of the program.
+    fn jump_to(&mut self, target: i64) -> Result<(), VmError> {
+        if target < 0 || target > self.program.len() as i64 {
+            return Err(VmError::PcOutOfBounds {
+                pc: self.instruction_pc,
+                opcode: self.current_opcode(),
+                target,
+            });
+        }
+        self.pc = target as usize;
+        Ok(())
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_vm() {
+        let test_vm = VM::new();
+        assert_eq!(test_vm.registers[16], 0);
+    }
+
+    #[test]
+    fn test_opcode_hlt() {
+        let mut test_vm = VM::new();
+        let test_bytes = vec![5, 0, 0, 0, 0, 0];
+        test_vm.program = test_bytes;
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.pc, 1);
+    }
+
+    #[test]
+    fn test_opcode_igl() {
+        let mut test_vm = VM::new();
+        let test_bytes = vec![200, 0, 0, 0];
+        test_vm.program = test_bytes;
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::IllegalOpcode { pc: 0, byte: 200 })
+        );
+    }
+
+    #[test]
+    fn test_opcode_load() {
+        let mut test_vm = VM::new();
+        // Load 500 to register 0 and load 250 to register 1.
+        test_vm.program = vec![0, 0, 1, 244, 0, 1, 0, 250];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 500);
+        assert_eq!(test_vm.registers[1], 250);
+    }
+
+    #[test]
+    fn test_opcode_add() {
+        let mut test_vm = VM::new();
+        // Load 500 to register 1, load 500 to register 2, add register 1 and 2, and store the
+        // result to register 0.
+        test_vm.program = vec![0, 1, 1, 244, 0, 2, 1, 244, 1, 1, 2, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 1000);
+    }
+
+    #[test]
+    fn test_opcode_sub() {
+        let mut test_vm = VM::new();
+        // Load 500 to register 1, load 500 to register 1, subtract register 2 from register 1 and
+        // store the result to register 0.
+        test_vm.program = vec![0, 1, 1, 244, 0, 2, 1, 244, 2, 1, 2, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_opcode_mul() {
+        let mut test_vm = VM::new();
+        // Load 500 to register 1, load 500 to register 2, multiply register 1 by register 2 and store
+        // the result to register 0.
+        test_vm.program = vec![0, 1, 1, 244, 0, 2, 1, 244, 3, 1, 2, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 250000);
+    }
+
+    #[test]
+    fn test_opcode_div() {
+        let mut test_vm = VM::new();
+        // Load 100 to register 1, load 3 to register 2, divide register 1 by register 2 and store
+        // the result to register 0.
+        test_vm.program = vec![0, 1, 0, 100, 0, 2, 0, 3, 4, 1, 2, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 33);
+        assert_eq!(test_vm.remainder, 1);
+    }
+
+    #[test]
+    fn test_opcode_jmp() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 1;
+        test_vm.program = vec![6, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 1);
+    }
+
+    #[test]
+    fn test_opcode_jmpf() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 2;
+        test_vm.program = vec![7, 0, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_opcode_jmpb() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 3;
+        test_vm.pc = 1;
+        test_vm.program = vec![0, 8, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_opcode_eq() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 10;
+        test_vm.program = vec![9, 0, 1, 9, 0, 1];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = 20;
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_opcode_neq() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 10;
+        test_vm.program = vec![32, 0, 1, 32, 0, 1];
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+        test_vm.registers[1] = 20;
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_opcode_gt() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = -20;
+        test_vm.program = vec![33, 0, 1, 33, 0, 1, 33, 0, 1];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = 10;
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+        test_vm.registers[1] = 20;
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_opcode_gte() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = -20;
+        test_vm.program = vec![34, 0, 1, 34, 0, 1, 34, 0, 1];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = 10;
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = 20;
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_opcode_lt() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -10;
+        test_vm.registers[1] = 20;
+        test_vm.program = vec![35, 0, 1, 35, 0, 1, 35, 0, 1];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = -10;
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+        test_vm.registers[1] = -20;
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_opcode_lte() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -10;
+        test_vm.registers[1] = 20;
+        test_vm.program = vec![36, 0, 1, 36, 0, 1, 36, 0, 1];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = -10;
+        test_vm.run_once().unwrap();
+        assert!(test_vm.equal_flag);
+        test_vm.registers[1] = -20;
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_opcode_jeq() {
+        let mut test_vm = VM::new();
+        test_vm.equal_flag = true;
+        // Load 7 to register 0, and set the counter the value of the register 0 if `equal_flag` is
+        // true.
+        test_vm.program = vec![0, 0, 0, 7, 10, 0, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.pc, 7)
+    }
+
+    #[test]
+    fn test_opcode_aloc() {
+        let mut test_vm = VM::new();
+        // Load 5 to register 0, and allocate the value stored in register 5 to the heap.
+        test_vm.program = vec![0, 0, 0, 5, 12, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap.len(), 5);
+    }
+
+    #[test]
+    fn test_opcode_free() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[0] = 3;
+        test_vm.registers[1] = 6;
+        // Free 3 bytes, and then free 6 bytes, which is more than is left.
+        test_vm.program = vec![41, 0, 41, 1];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap.len(), 5);
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmError::HeapOverflow {
+                pc: 2,
+                opcode: Opcode::FREE,
+                requested: -1
+            })
+        );
+    }
+
+    #[test]
+    fn test_opcode_storeb_loadb() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[0] = 0x1FF;
+        test_vm.registers[1] = 3;
+        // Store the lowest byte of register 0 at address 3, and load it back to register 2.
+        test_vm.program = vec![38, 0, 1, 37, 2, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0, 0, 0xFF]);
+        assert_eq!(test_vm.registers[2], 0xFF);
+    }
+
+    #[test]
+    fn test_opcode_storew_loadw() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 6];
+        test_vm.registers[0] = -2;
+        test_vm.registers[1] = 1;
+        // Store register 0 at address 1, and load it back to register 2.
+        test_vm.program = vec![40, 0, 1, 39, 2, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0xFF, 0xFF, 0xFF, 0xFE, 0]);
+        assert_eq!(test_vm.registers[2], -2);
+    }
+
+    #[test]
+    fn test_heap_out_of_bounds() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = 1;
+        // Load a word at address 1, which would read one byte past the heap.
+        test_vm.program = vec![39, 0, 1];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::HeapOutOfBounds {
+                pc: 0,
+                opcode: Opcode::LOADW,
+                address: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_opcode_inc() {
+        let mut test_vm = VM::new();
+        // Load 5 to register 0, and increments the value stored in register 0 by 1.
+        test_vm.program = vec![0, 0, 0, 5, 13, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 6);
+    }
+
+    #[test]
+    fn test_opcode_dec() {
+        let mut test_vm = VM::new();
+        // Load 5 to register 0, and decrements the value stored in register 0 by 1.
+        test_vm.program = vec![0, 0, 0, 5, 14, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 4);
+    }
+
+    #[test]
+    fn test_div_by_zero() {
+        let mut test_vm = VM::new();
+        // Load 100 to register 1, and divide register 1 by register 2, which holds 0.
+        test_vm.program = vec![0, 1, 0, 100, 4, 1, 2, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::DivideByZero {
+                pc: 4,
+                opcode: Opcode::DIV
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![13, 32];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::InvalidRegister {
+                pc: 0,
+                opcode: Opcode::INC,
+                register: 32
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 1];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::TruncatedInstruction {
+                pc: 0,
+                opcode: Opcode::LOAD
+            })
+        );
+    }
+
+    #[test]
+    fn test_jmpb_out_of_bounds() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![8, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::PcOutOfBounds {
+                pc: 0,
+                opcode: Opcode::JMPB,
+                target: -8
+            })
+        );
+    }
+
+    #[test]
+    fn test_aloc_negative() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.program = vec![12, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::InvalidAllocation {
+                pc: 0,
+                opcode: Opcode::ALOC,
+                size: -1
+            })
+        );
+    }
+
+    #[test]
+    fn test_max_heap_size() {
+        let mut test_vm = VM::with_config(VmConfig {
+            max_heap_size: 8,
+            ..VmConfig::default()
+        });
+        test_vm.registers[0] = 6;
+        // Allocate 6 bytes twice, which would grow the heap past 8 bytes.
+        test_vm.program = vec![12, 0, 12, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::HeapOverflow {
+                pc: 2,
+                opcode: Opcode::ALOC,
+                requested: 12
+            })
+        );
+        assert_eq!(test_vm.heap.len(), 6);
+
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.program = vec![12, 0];
+        assert!(matches!(test_vm.run(), Err(VmError::HeapOverflow { .. })));
+    }
+
+    #[test]
+    fn test_opcode_call_ret() {
+        let mut test_vm = VM::new();
+        // Call the subroutine at 4, which loads 7 to register 0 and returns to the halt at 3.
+        test_vm.program = vec![15, 0, 4, 5, 0, 0, 0, 7, 16];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0], 7);
+        assert_eq!(test_vm.get_stack_pointer(), 0);
+    }
+
+    #[test]
+    fn test_opcode_push_pop() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 42;
+        // Push register 0, and pop the value into register 1.
+        test_vm.program = vec![17, 0, 18, 1];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.get_stack(), &vec![42]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], 42);
+        assert_eq!(test_vm.get_stack_pointer(), 0);
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![16];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::StackUnderflow {
+                pc: 0,
+                opcode: Opcode::RET
+            })
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let mut test_vm = VM::with_config(VmConfig {
+            max_stack_depth: 2,
+            ..VmConfig::default()
+        });
+        // Call itself forever.
+        test_vm.program = vec![15, 0, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::StackOverflow {
+                pc: 0,
+                opcode: Opcode::CALL,
+                depth: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_load() {
+        let mut program = Program::new(vec![5, 0, 0, 0, 5, 5]);
+        program.entry_point = 1;
+        let mut test_vm = VM::new();
+        test_vm.load(&program.to_bytes()).unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0], 5);
+        assert_eq!(test_vm.load(&[0, 1, 2, 3]), Err(ProgramError::BadMagic));
+    }
+
+    #[test]
+    fn test_opcode_loadf() {
+        let mut test_vm = VM::new();
+        // Load 1.5 to float register 2.
+        let mut program = vec![19, 2];
+        program.extend_from_slice(&1.5f64.to_bits().to_be_bytes());
+        test_vm.program = program;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[2], 1.5);
+    }
+
+    #[test]
+    fn test_float_arithmetic() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 7.5;
+        test_vm.float_registers[1] = 2.5;
+        // ADDF, SUBF, MULF and DIVF of float registers 0 and 1 into float registers 2 to 5.
+        test_vm.program = vec![20, 0, 1, 2, 21, 0, 1, 3, 22, 0, 1, 4, 23, 0, 1, 5];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[2], 10.0);
+        assert_eq!(test_vm.float_registers[3], 5.0);
+        assert_eq!(test_vm.float_registers[4], 18.75);
+        assert_eq!(test_vm.float_registers[5], 3.0);
+    }
+
+    #[test]
+    fn test_float_comparisons() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 1.0;
+        test_vm.float_registers[1] = 2.0;
+        // EQF, NEQF, GTF, GTEF, LTF and LTEF of float registers 0 and 1.
+        test_vm.program = vec![24, 0, 1, 25, 0, 1, 26, 0, 1, 27, 0, 1, 28, 0, 1, 29, 0, 1];
+        let mut results = vec![];
+        for _ in 0..6 {
+            test_vm.run_once().unwrap();
+            results.push(test_vm.equal_flag);
+        }
+        assert_eq!(results, vec![false, true, false, false, true, true]);
+    }
+
+    #[test]
+    fn test_float_conversions() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -3;
+        test_vm.float_registers[1] = 2.9;
+        // ITOF register 0 into float register 0, and FTOI float register 1 into register 1.
+        test_vm.program = vec![30, 0, 0, 31, 1, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[0], -3.0);
+        assert_eq!(test_vm.registers[1], 2);
+    }
+
+    /// An output that keeps what was written so that tests can read it back.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_opcode_prti_prts_prth() {
+        let mut test_vm = VM::new();
+        let output = SharedBuffer::default();
+        test_vm.set_output(Box::new(output.clone()));
+        test_vm.heap = vec![b'o', b'k'];
+        test_vm.ro_data = b"a = \0".to_vec();
+        test_vm.registers[0] = -42;
+        test_vm.registers[2] = 2;
+        // Print "a = ", register 0, and the two bytes at heap address 0.
+        test_vm.program = vec![43, 0, 0, 42, 0, 44, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(*output.0.borrow(), b"a = -42ok".to_vec());
+    }
+
+    #[test]
+    fn test_prts_unterminated() {
+        let mut test_vm = VM::new();
+        test_vm.set_output(Box::new(SharedBuffer::default()));
+        test_vm.ro_data = b"abc".to_vec();
+        test_vm.program = vec![43, 0, 1];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::DataOutOfBounds {
+                pc: 0,
+                opcode: Opcode::PRTS,
+                offset: 1
+            })
+        );
+    }
+
+    fn native_add(_: &mut VM, args: &[i32]) -> Result<i32, String> {
+        Ok(args[0] + args[1])
+    }
+
+    fn native_fail(vm: &mut VM, _: &[i32]) -> Result<i32, String> {
+        Err(format!("heap has {} bytes", vm.get_heap().len()))
+    }
+
+    #[test]
+    fn test_opcode_calln() {
+        let mut test_vm = VM::new();
+        assert_eq!(test_vm.register_native("fail", 0, native_fail), 0);
+        assert_eq!(test_vm.register_native("add", 2, native_add), 1);
+        assert_eq!(test_vm.native_id("add"), Some(1));
+        test_vm.registers[0] = 2;
+        test_vm.registers[1] = 3;
+        test_vm.program = vec![45, 0, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 5);
+    }
+
+    #[test]
+    fn test_calln_errors() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![45, 0, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::UnknownNative {
+                pc: 0,
+                opcode: Opcode::CALLN,
+                id: 0
+            })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.register_native("fail", 0, native_fail);
+        test_vm.program = vec![45, 0, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmError::Native {
+                pc: 0,
+                opcode: Opcode::CALLN,
+                name: String::from("fail"),
+                message: String::from("heap has 0 bytes")
+            })
+        );
+    }
+
+    #[test]
+    fn test_run_with_budget() {
+        let mut test_vm = VM::new();
+        // Jump to itself forever.
+        test_vm.program = vec![0, 0, 0, 4, 6, 0];
+        assert_eq!(test_vm.run_with_budget(100), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(test_vm.pc, 4);
+
+        let mut test_vm = VM::new();
+        test_vm.program = vec![13, 0, 13, 0, 5];
+        assert_eq!(test_vm.run_with_budget(1), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(test_vm.registers[0], 1);
+        // Running again resumes where it stopped.
+        assert_eq!(test_vm.run_with_budget(2), Ok(ExitStatus::Halted));
+        assert_eq!(test_vm.registers[0], 2);
+    }
+
+    #[test]
+    fn test_opcode_costs() {
+        let mut config = VmConfig::default();
+        config.opcode_costs.insert(Opcode::INC, 10);
+        let mut test_vm = VM::with_config(config);
+        test_vm.program = vec![13, 0, 13, 0, 5];
+        assert_eq!(test_vm.run_with_budget(15), Ok(ExitStatus::OutOfFuel));
+        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(test_vm.pc, 2);
+    }
+
+    #[test]
+    fn test_tracer() {
+        use self::trace::TraceBuffer;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut test_vm = VM::new();
+        let buffer = Rc::new(RefCell::new(TraceBuffer::new(8)));
+        test_vm.set_tracer(Box::new(buffer.clone()));
+        // Load 1 to register 0, compare it to register 1, and halt.
+        test_vm.program = vec![0, 0, 0, 1, 32, 0, 1, 5];
+        test_vm.run().unwrap();
+
+        let lines: Vec<String> = buffer
+            .borrow()
+            .entries()
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "0000  LOAD $0 #1  ; $0=1",
+                "0004  NEQ $0 $1  ; flag=true",
+                "0007  HLT"
+            ]
+        );
+
+        // A program without HLT finishes by running off the end of its code.
+        let mut test_vm = VM::new();
+        let buffer = Rc::new(RefCell::new(TraceBuffer::new(8)));
+        test_vm.set_tracer(Box::new(buffer.clone()));
+        test_vm.program = vec![13, 0];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Finished));
+        assert_eq!(buffer.borrow().entries().len(), 1);
+        assert_eq!(
+            buffer.borrow().entries()[0].to_string(),
+            "0000  INC $0  ; $0=1"
+        );
+    }
+
+    #[test]
+    fn test_profiling() {
+        let mut test_vm = VM::new();
+        test_vm.enable_profiling(true);
+        // Increment register 0 twice, and halt.
+        test_vm.program = vec![13, 0, 13, 0, 5];
+        test_vm.run().unwrap();
+        let profile = test_vm.take_profile().unwrap();
+        assert_eq!(profile.total(), 3);
+        assert_eq!(profile.opcode_counts()[&Opcode::INC], 2);
+        assert_eq!(profile.pc_counts()[&4], 1);
+        assert!(test_vm.get_profile().is_none());
+
+        // A native function may replace the program while it is being profiled.
+        fn unload(vm: &mut VM, _: &[i32]) -> Result<i32, String> {
+            vm.load_program(&Program::new(vec![]));
+            Ok(0)
+        }
+        let mut test_vm = VM::new();
+        let id = test_vm.register_native("unload", 0, unload);
+        test_vm.enable_profiling(false);
+        test_vm.program = vec![45, 0, id as u8];
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Finished));
+        assert_eq!(
+            test_vm.get_profile().unwrap().opcode_counts()[&Opcode::CALLN],
+            1
+        );
+    }
+}
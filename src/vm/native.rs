@@ -0,0 +1,15 @@
+use super::VM;
+
+/// A function implemented by the embedder that bytecode can call with `CALLN`. It receives the
+/// values of the argument registers and returns the value to store to register 0, or a message
+/// describing why it failed.
+pub type NativeFunction = fn(&mut VM, &[i32]) -> Result<i32, String>;
+
+/// A native function registered on a `VM`.
+#[derive(Clone, Debug)]
+pub struct Native {
+    pub name: String,
+    /// Number of arguments, which are taken from registers 0 and up.
+    pub arity: u8,
+    pub function: NativeFunction,
+}
@@ -0,0 +1,150 @@
+use crate::assembler::symbol_table::{SymbolTable, SymbolType};
+use crate::disassembler;
+use crate::instruction::Opcode;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// Execution counts collected while profiling is enabled with `VM::enable_profiling`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    /// Whether the wall-clock time of every instruction is measured
+    timed: bool,
+    pc_counts: HashMap<usize, u64>,
+    opcode_counts: HashMap<Opcode, u64>,
+    opcode_times: HashMap<Opcode, Duration>,
+}
+
+impl Profile {
+    pub fn new(timed: bool) -> Self {
+        Profile {
+            timed,
+            ..Profile::default()
+        }
+    }
+
+    pub fn is_timed(&self) -> bool {
+        self.timed
+    }
+
+    /// Records one execution of the instruction at `pc`, and the time it took if timed.
+    pub fn record(&mut self, pc: usize, opcode: Opcode, time: Option<Duration>) {
+        *self.pc_counts.entry(pc).or_insert(0) += 1;
+        *self.opcode_counts.entry(opcode).or_insert(0) += 1;
+        if let Some(time) = time {
+            *self.opcode_times.entry(opcode).or_default() += time;
+        }
+    }
+
+    /// Returns how many times the instruction at each offset was executed.
+    pub fn pc_counts(&self) -> &HashMap<usize, u64> {
+        &self.pc_counts
+    }
+
+    /// Returns how many times each opcode was executed.
+    pub fn opcode_counts(&self) -> &HashMap<Opcode, u64> {
+        &self.opcode_counts
+    }
+
+    /// Returns the total number of instructions executed.
+    pub fn total(&self) -> u64 {
+        self.pc_counts.values().sum()
+    }
+
+    /// Returns the counts of instructions executed under each code label in `symbols`. An
+    /// instruction counts towards the closest label before it.
+    pub fn label_counts(&self, symbols: &SymbolTable) -> Vec<(String, u64)> {
+        let mut labels = BTreeMap::new();
+        for symbol in symbols.symbols() {
+            if *symbol.symbol_type() == SymbolType::Label {
+                labels.insert(symbol.offset() as usize, symbol.name().to_string());
+            }
+        }
+
+        let mut counts: BTreeMap<usize, (String, u64)> = BTreeMap::new();
+        for (&pc, &count) in &self.pc_counts {
+            let (offset, name) = match labels.range(..=pc).next_back() {
+                Some((&offset, name)) => (offset, name.clone()),
+                None => (0, String::from("<start>")),
+            };
+            counts.entry(offset).or_insert((name, 0)).1 += count;
+        }
+        counts.into_values().collect()
+    }
+
+    /// Formats the `top` most executed instructions, the counts per opcode, and the counts per
+    /// label in `symbols`.
+    pub fn report(&self, code: &[u8], symbols: &SymbolTable, top: usize) -> String {
+        let mut text = format!("instructions executed: {}\n", self.total());
+
+        let mut hot: Vec<(usize, u64)> = self.pc_counts.iter().map(|(&pc, &n)| (pc, n)).collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        text.push_str("hot instructions:\n");
+        for (pc, count) in hot.into_iter().take(top) {
+            let instruction = if pc < code.len() {
+                disassembler::disassemble_instruction(code, pc).0
+            } else {
+                String::new()
+            };
+            text.push_str(&format!("    {:>10}  {:04}  {}\n", count, pc, instruction));
+        }
+
+        let mut opcodes: Vec<(Opcode, u64)> =
+            self.opcode_counts.iter().map(|(&op, &n)| (op, n)).collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1).then((a.0 as u8).cmp(&(b.0 as u8))));
+        text.push_str("by opcode:\n");
+        for (opcode, count) in opcodes {
+            text.push_str(&format!("    {:>10}  {:?}", count, opcode));
+            if let Some(time) = self.opcode_times.get(&opcode) {
+                text.push_str(&format!("  {:?}", time));
+            }
+            text.push('\n');
+        }
+
+        let labels = self.label_counts(symbols);
+        if !labels.is_empty() {
+            text.push_str("by label:\n");
+            for (label, count) in labels {
+                text.push_str(&format!("    {:>10}  {}\n", count, label));
+            }
+        }
+        text
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::symbol_table::Symbol;
+
+    #[test]
+    fn test_report() {
+        // INC $0, then a loop label at 2 with INC $1 and HLT.
+        let code = vec![13, 0, 13, 1, 5];
+        let mut profile = Profile::new(false);
+        profile.record(0, Opcode::INC, None);
+        for _ in 0..3 {
+            profile.record(2, Opcode::INC, None);
+        }
+        profile.record(4, Opcode::HLT, None);
+        let mut symbols = SymbolTable::new();
+        symbols.add_symbol(Symbol::new(String::from("loop"), SymbolType::Label, 2));
+
+        assert_eq!(
+            profile.label_counts(&symbols),
+            vec![(String::from("<start>"), 1), (String::from("loop"), 4)]
+        );
+        let expected = "instructions executed: 5
+hot instructions:
+             3  0002  INC $1
+             1  0000  INC $0
+by opcode:
+             4  INC
+             1  HLT
+by label:
+             1  <start>
+             4  loop
+";
+        assert_eq!(profile.report(&code, &symbols, 2), expected);
+    }
+}
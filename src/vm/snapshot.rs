@@ -0,0 +1,222 @@
+use super::VM;
+use crate::program::{Reader, Truncated};
+use std::fmt;
+
+/// Magic bytes every snapshot starts with.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"FVMS";
+/// Version of the snapshot format written by this build.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// Errors raised while restoring a snapshot.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotError {
+    /// The snapshot does not start with `SNAPSHOT_MAGIC`.
+    BadMagic,
+    /// The snapshot was written by an incompatible version of the format.
+    UnsupportedVersion(u16),
+    /// The snapshot ended before all of the state was read.
+    Truncated,
+    /// The snapshot holds state that a VM cannot be in.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not an fvm snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported snapshot version {} (expected {})",
+                v, SNAPSHOT_VERSION
+            ),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Invalid(msg) => write!(f, "invalid snapshot: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<Truncated> for SnapshotError {
+    fn from(_: Truncated) -> Self {
+        SnapshotError::Truncated
+    }
+}
+
+impl VM {
+    /// Serializes the execution state of the VM.
+    ///
+    /// The layout is, with all integers big-endian:
+    ///
+    /// ```text
+    /// magic            [u8; 4]  "FVMS"
+    /// version          u16
+    /// pc               u64
+    /// instruction pc   u64
+    /// registers        [i32; 32]
+    /// float registers  [f64; 32]
+    /// remainder        u32
+    /// equal flag       u8       0 or 1
+    /// program          u32 length, then the bytes
+    /// read-only data   u32 length, then the bytes
+    /// heap             u32 length, then the bytes
+    /// stack            u32 length, then that many i32
+    /// ```
+    ///
+    /// The configuration, native functions, output, tracer and profile belong to the embedder
+    /// and are not included.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&(self.pc as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.instruction_pc as u64).to_be_bytes());
+        for register in &self.registers {
+            bytes.extend_from_slice(&register.to_be_bytes());
+        }
+        for register in &self.float_registers {
+            bytes.extend_from_slice(&register.to_bits().to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.remainder.to_be_bytes());
+        bytes.push(u8::from(self.equal_flag));
+        for section in [&self.program, &self.ro_data, &self.heap] {
+            bytes.extend_from_slice(&(section.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(section);
+        }
+        bytes.extend_from_slice(&(self.stack.len() as u32).to_be_bytes());
+        for value in &self.stack {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Replaces the execution state of the VM with a snapshot taken by `VM::snapshot`. The VM is
+    /// left unchanged if the snapshot is invalid.
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader::new(bytes);
+        if reader
+            .take(SNAPSHOT_MAGIC.len())
+            .map_err(|_| SnapshotError::BadMagic)?
+            != SNAPSHOT_MAGIC
+        {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let pc = reader.u64()? as usize;
+        let instruction_pc = reader.u64()? as usize;
+        let mut registers = [0; 32];
+        for register in registers.iter_mut() {
+            *register = reader.u32()? as i32;
+        }
+        let mut float_registers = [0.0; 32];
+        for register in float_registers.iter_mut() {
+            *register = f64::from_bits(reader.u64()?);
+        }
+        let remainder = reader.u32()?;
+        let equal_flag = match reader.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(SnapshotError::Invalid("equal flag is not 0 or 1")),
+        };
+        let program = read_section(&mut reader)?;
+        let ro_data = read_section(&mut reader)?;
+        let heap = read_section(&mut reader)?;
+        let stack_len = reader.u32()? as usize;
+        let stack = reader
+            .take(stack_len.checked_mul(4).ok_or(SnapshotError::Truncated)?)?
+            .chunks(4)
+            .map(|b| i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+
+        if !reader.is_end() {
+            return Err(SnapshotError::Invalid("trailing bytes"));
+        }
+        if pc > program.len() {
+            return Err(SnapshotError::Invalid("pc is outside of the program"));
+        }
+
+        self.pc = pc;
+        self.instruction_pc = instruction_pc;
+        self.registers = registers;
+        self.float_registers = float_registers;
+        self.remainder = remainder;
+        self.equal_flag = equal_flag;
+        self.program = program;
+        self.ro_data = ro_data;
+        self.heap = heap;
+        self.stack = stack;
+        Ok(())
+    }
+}
+
+/// Reads a length-prefixed byte section.
+fn read_section(reader: &mut Reader) -> Result<Vec<u8>, SnapshotError> {
+    let len = reader.u32()? as usize;
+    Ok(reader.take(len)?.to_vec())
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::ExitStatus;
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut test_vm = VM::new();
+        // Count register 0 up to 3 with LT driving JEQ, pushing every value.
+        test_vm.program = vec![0, 1, 0, 3, 0, 2, 0, 8, 13, 0, 17, 0, 35, 0, 1, 10, 2, 5];
+        test_vm.heap = vec![1, 2, 3];
+        test_vm.ro_data = b"hi\0".to_vec();
+        test_vm.float_registers[3] = -0.5;
+        for _ in 0..6 {
+            test_vm.run_once().unwrap();
+        }
+
+        let snapshot = test_vm.snapshot();
+        let mut restored = VM::new();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+
+        assert_eq!(test_vm.run(), Ok(ExitStatus::Halted));
+        assert_eq!(restored.run(), Ok(ExitStatus::Halted));
+        assert_eq!(restored.snapshot(), test_vm.snapshot());
+        assert_eq!(restored.get_stack(), &vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_invalid_snapshots() {
+        let snapshot = VM::new().snapshot();
+        let mut test_vm = VM::new();
+        assert_eq!(test_vm.restore(b"FVMB"), Err(SnapshotError::BadMagic));
+        assert_eq!(
+            test_vm.restore(&snapshot[..snapshot.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+
+        let mut bytes = snapshot.clone();
+        bytes[5] = 2;
+        assert_eq!(
+            test_vm.restore(&bytes),
+            Err(SnapshotError::UnsupportedVersion(2))
+        );
+
+        let mut bytes = snapshot.clone();
+        bytes.push(0);
+        assert_eq!(
+            test_vm.restore(&bytes),
+            Err(SnapshotError::Invalid("trailing bytes"))
+        );
+
+        let mut bytes = snapshot;
+        bytes[13] = 1;
+        assert_eq!(
+            test_vm.restore(&bytes),
+            Err(SnapshotError::Invalid("pc is outside of the program"))
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use crate::disassembler;
+use crate::instruction::Instruction;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// What an executed instruction changed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    /// Offset of the instruction.
+    pub pc: usize,
+    pub instruction: Instruction,
+    /// Registers whose value changed, with their new values.
+    pub register_writes: Vec<(usize, i32)>,
+    /// Float registers whose value changed, with their new values.
+    pub float_register_writes: Vec<(usize, f64)>,
+    /// The new value of the comparison flag, if it changed.
+    pub equal_flag: Option<bool>,
+}
+
+/// Formats the entry as one line, e.g. `0004  ADD $0 $1 $2  ; $2=5`.
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut code = vec![];
+        self.instruction.encode(&mut code);
+        let (text, _) = disassembler::disassemble_instruction(&code, 0);
+        write!(f, "{:04}  {}", self.pc, text)?;
+
+        let mut changes = vec![];
+        for (register, value) in &self.register_writes {
+            changes.push(format!("${}={}", register, value));
+        }
+        for (register, value) in &self.float_register_writes {
+            changes.push(format!("$f{}={:?}", register, value));
+        }
+        if let Some(flag) = self.equal_flag {
+            changes.push(format!("flag={}", flag));
+        }
+        if !changes.is_empty() {
+            write!(f, "  ; {}", changes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives an entry for every instruction the VM executes while it is set with
+/// `VM::set_tracer`.
+pub trait Tracer {
+    fn record(&mut self, entry: &TraceEntry);
+}
+
+/// Lets the embedder keep a handle to a tracer that is owned by the VM.
+impl<T: Tracer> Tracer for Rc<RefCell<T>> {
+    fn record(&mut self, entry: &TraceEntry) {
+        self.borrow_mut().record(entry);
+    }
+}
+
+/// Keeps the last `capacity` entries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceBuffer {
+    capacity: usize,
+    entries: VecDeque<TraceEntry>,
+}
+
+impl TraceBuffer {
+    pub fn new(capacity: usize) -> Self {
+        TraceBuffer {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Returns the recorded entries, oldest first.
+    pub fn entries(&self) -> &VecDeque<TraceEntry> {
+        &self.entries
+    }
+}
+
+impl Tracer for TraceBuffer {
+    fn record(&mut self, entry: &TraceEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry.clone());
+    }
+}
+
+/// Writes every entry as a line of text. Writing stops at the first error, which `finish`
+/// returns.
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(writer: W) -> Self {
+        TraceWriter {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Flushes the writer, returning the first error raised while writing, if any.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Tracer for TraceWriter<W> {
+    fn record(&mut self, entry: &TraceEntry) {
+        if self.error.is_none() {
+            if let Err(err) = writeln!(self.writer, "{}", entry) {
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+/// The first line at which two traces differ. A missing line means that trace ended first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceDiff {
+    /// Line number, starting at 1.
+    pub line: usize,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+/// Compares two traces written by `TraceWriter`, returning where they first differ.
+pub fn diff(left: &str, right: &str) -> Option<TraceDiff> {
+    let mut left_lines = left.lines();
+    let mut right_lines = right.lines();
+    let mut line = 1;
+    loop {
+        match (left_lines.next(), right_lines.next()) {
+            (None, None) => return None,
+            (l, r) if l != r => {
+                return Some(TraceDiff {
+                    line,
+                    left: l.map(String::from),
+                    right: r.map(String::from),
+                })
+            }
+            _ => line += 1,
+        }
+    }
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Opcode;
+
+    fn entry(pc: usize) -> TraceEntry {
+        TraceEntry {
+            pc,
+            instruction: Instruction::with_operands(Opcode::INC, &[0]),
+            register_writes: vec![(0, pc as i32)],
+            float_register_writes: vec![],
+            equal_flag: None,
+        }
+    }
+
+    #[test]
+    fn test_format_entry() {
+        let mut entry = entry(4);
+        entry.float_register_writes = vec![(1, 2.5)];
+        entry.equal_flag = Some(true);
+        assert_eq!(entry.to_string(), "0004  INC $0  ; $0=4 $f1=2.5 flag=true");
+    }
+
+    #[test]
+    fn test_trace_buffer() {
+        let mut buffer = TraceBuffer::new(2);
+        for pc in 0..3 {
+            buffer.record(&entry(pc));
+        }
+        assert_eq!(
+            buffer.entries().iter().cloned().collect::<Vec<_>>(),
+            vec![entry(1), entry(2)]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff("a\nb\n", "a\nb\n"), None);
+        assert_eq!(
+            diff("a\nb\nc", "a\nx\nc"),
+            Some(TraceDiff {
+                line: 2,
+                left: Some(String::from("b")),
+                right: Some(String::from("x")),
+            })
+        );
+        assert_eq!(
+            diff("a", "a\nb"),
+            Some(TraceDiff {
+                line: 2,
+                left: None,
+                right: Some(String::from("b")),
+            })
+        );
+    }
+}